@@ -1,10 +1,10 @@
 // In this example we will construct a single CPU x86 VM which will execute
-// "inb 0x01" at ring 0 with paging disabled
+// "inb 0x01; hlt" at ring 0 with paging disabled
 
 extern crate kvm;
 extern crate memmap;
 
-use kvm::{Capability, Exit, IoDirection, System, Vcpu, VirtualMachine};
+use kvm::{Capability, System, Vcpu, VcpuExit, VirtualMachine};
 use memmap::{Mmap, Protection};
 
 fn main() {
@@ -13,23 +13,24 @@ fn main() {
                             .unwrap();
     let slice = unsafe { anon_mmap.as_mut_slice() };
 
-    // Write the "inb 0x01" instruction at 1MB
+    // Write the "inb 0x01" instruction at 1MB, followed by "hlt"
     slice[0x100000] = 0xe4;
     slice[0x100001] = 0x01;
+    slice[0x100002] = 0xf4;
 
     // Initialize the KVM system
     let sys = System::initialize().unwrap();
 
     // Create a Virtual Machine
-    let mut vm = VirtualMachine::create(&sys).unwrap();
+    let vm = VirtualMachine::create(&sys).unwrap();
 
     // Ensure that the VM supports memory backing with user memory
     assert!(vm.check_capability(Capability::UserMemory) > 0);
     // Set the 2 MB range to start at physical address 0
-    vm.set_user_memory_region(0, slice, 0).unwrap();
+    vm.set_user_memory_region(0, anon_mmap, 0).unwrap();
 
     // Create a new VCPU
-    let mut vcpu = Vcpu::create(&mut vm).unwrap();
+    let mut vcpu = Vcpu::create(&vm).unwrap();
 
     // Set supported CPUID (KVM fails without doing this)
     let mut cpuid = sys.get_supported_cpuid().unwrap();
@@ -65,16 +66,29 @@ fn main() {
     vcpu.set_regs(&regs).unwrap();
 
     // Actually run the VCPU
-    let run = unsafe { vcpu.run() }.unwrap();
-
-    // Ensure that the exit reason we get back indicates that the I/O
-    // instruction was executed
-    assert!(run.exit_reason == Exit::Io);
-    let io = unsafe { *run.io() };
-    assert!(io.direction == IoDirection::In);
-    assert!(io.size == 1);
-    assert!(io.port == 0x1);
-    unsafe {
-        println!("{:#?}", *run.io());
+    {
+        let exit = vcpu.run().unwrap();
+        println!("{:#?}", exit);
+
+        // Ensure that the exit reason we get back indicates that the I/O
+        // instruction was executed
+        match exit {
+            VcpuExit::IoIn { port, size, data } => {
+                assert!(port == 0x1);
+                assert!(size == 1);
+                // Respond to the "inb"
+                data[0] = 0x42;
+            }
+            _ => panic!("Unexpected exit"),
+        }
     }
+
+    // Running again completes the "inb" and executes the "hlt"
+    match vcpu.run().unwrap() {
+        VcpuExit::Hlt => {}
+        _ => panic!("Unexpected exit"),
+    }
+    let regs = vcpu.get_regs().unwrap();
+    assert!(regs.rax & 0xff == 0x42);
+    println!("Read {:#x} from port 0x1", regs.rax & 0xff);
 }
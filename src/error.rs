@@ -0,0 +1,284 @@
+// Copyright 2015 Dan Schatzberg.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::result;
+
+/// Result type used by this crate
+pub type Result<T> = result::Result<T, Error>;
+
+/// The KVM operation which failed
+#[allow(missing_docs)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Operation {
+    CreateVm,
+    GetVcpuMmapSize,
+    GetSupportedCpuid,
+    GetMsrIndexList,
+    GetMsrFeatureIndexList,
+    GetMsrFeatures,
+    Run,
+    GetRegs,
+    SetRegs,
+    GetSregs,
+    SetSregs,
+    SetCpuid2,
+    Interrupt,
+    Nmi,
+    Smi,
+    GetVcpuEvents,
+    SetVcpuEvents,
+    GetFpu,
+    SetFpu,
+    GetMsrs,
+    SetMsrs,
+    GetLapic,
+    SetLapic,
+    CreateIrqchip,
+    IrqLine,
+    GetIrqchip,
+    SetIrqchip,
+    SignalMsi,
+    SetGsiRouting,
+    CreatePit2,
+    GetPit2,
+    SetPit2,
+    ReinjectControl,
+    Irqfd,
+    Ioeventfd,
+    GetDirtyLog,
+    ClearDirtyLog,
+    EnableCap,
+    ResetDirtyRings,
+}
+
+impl Operation {
+    /// Name of the ioctl used to perform this operation
+    pub fn ioctl_name(&self) -> &'static str {
+        match *self {
+            Operation::CreateVm => "KVM_CREATE_VM",
+            Operation::GetVcpuMmapSize => "KVM_GET_VCPU_MMAP_SIZE",
+            Operation::GetSupportedCpuid => "KVM_GET_SUPPORTED_CPUID",
+            Operation::GetMsrIndexList => "KVM_GET_MSR_INDEX_LIST",
+            Operation::GetMsrFeatureIndexList => {
+                "KVM_GET_MSR_FEATURE_INDEX_LIST"
+            }
+            Operation::GetMsrFeatures => "KVM_GET_MSRS",
+            Operation::Run => "KVM_RUN",
+            Operation::GetRegs => "KVM_GET_REGS",
+            Operation::SetRegs => "KVM_SET_REGS",
+            Operation::GetSregs => "KVM_GET_SREGS",
+            Operation::SetSregs => "KVM_SET_SREGS",
+            Operation::SetCpuid2 => "KVM_SET_CPUID2",
+            Operation::Interrupt => "KVM_INTERRUPT",
+            Operation::Nmi => "KVM_NMI",
+            Operation::Smi => "KVM_SMI",
+            Operation::GetVcpuEvents => "KVM_GET_VCPU_EVENTS",
+            Operation::SetVcpuEvents => "KVM_SET_VCPU_EVENTS",
+            Operation::GetFpu => "KVM_GET_FPU",
+            Operation::SetFpu => "KVM_SET_FPU",
+            Operation::GetMsrs => "KVM_GET_MSRS",
+            Operation::SetMsrs => "KVM_SET_MSRS",
+            Operation::GetLapic => "KVM_GET_LAPIC",
+            Operation::SetLapic => "KVM_SET_LAPIC",
+            Operation::CreateIrqchip => "KVM_CREATE_IRQCHIP",
+            Operation::IrqLine => "KVM_IRQ_LINE",
+            Operation::GetIrqchip => "KVM_GET_IRQCHIP",
+            Operation::SetIrqchip => "KVM_SET_IRQCHIP",
+            Operation::SignalMsi => "KVM_SIGNAL_MSI",
+            Operation::SetGsiRouting => "KVM_SET_GSI_ROUTING",
+            Operation::CreatePit2 => "KVM_CREATE_PIT2",
+            Operation::GetPit2 => "KVM_GET_PIT2",
+            Operation::SetPit2 => "KVM_SET_PIT2",
+            Operation::ReinjectControl => "KVM_REINJECT_CONTROL",
+            Operation::Irqfd => "KVM_IRQFD",
+            Operation::Ioeventfd => "KVM_IOEVENTFD",
+            Operation::GetDirtyLog => "KVM_GET_DIRTY_LOG",
+            Operation::ClearDirtyLog => "KVM_CLEAR_DIRTY_LOG",
+            Operation::EnableCap => "KVM_ENABLE_CAP",
+            Operation::ResetDirtyRings => "KVM_RESET_DIRTY_RINGS",
+        }
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(self.ioctl_name())
+    }
+}
+
+/// Errors returned by this crate
+#[derive(Debug)]
+pub enum Error {
+    /// `/dev/kvm` could not be opened
+    Open(io::Error),
+    /// KVM reported an API version other than the one this crate supports
+    ApiVersion(i32),
+    /// An ioctl on the `System` or a `VirtualMachine` failed
+    Ioctl {
+        /// The failed operation
+        op: Operation,
+        /// The error number reported by the kernel
+        errno: i32,
+    },
+    /// An ioctl on a `Vcpu` failed
+    Vcpu {
+        /// Id of the `Vcpu`
+        id: u32,
+        /// The failed operation
+        op: Operation,
+        /// The error number reported by the kernel
+        errno: i32,
+    },
+    /// Creating a `Vcpu` failed
+    CreateVcpu {
+        /// Id of the `Vcpu`
+        id: u32,
+        /// The error number reported by the kernel
+        errno: i32,
+    },
+    /// Mapping the run area of a `Vcpu` failed
+    MapVcpu {
+        /// Id of the `Vcpu`
+        id: u32,
+        /// The underlying error
+        error: io::Error,
+    },
+    /// Creating, signalling or reading an `EventFd` failed
+    EventFd(io::Error),
+    /// Creating a `Vcpu` would exceed `System::max_vcpus`
+    TooManyVcpus {
+        /// The maximum number of `Vcpu`s
+        max: u32,
+    },
+    /// Establishing a guest memory mapping failed
+    SetUserMemoryRegion {
+        /// The memory slot
+        slot: u32,
+        /// Guest physical address of the mapping
+        guest_phys_addr: u64,
+        /// The error number reported by the kernel
+        errno: i32,
+    },
+    /// No memory slot is free
+    NoFreeMemorySlots {
+        /// The maximum number of memory slots
+        max: u32,
+    },
+    /// The memory slot is not in use
+    InvalidMemorySlot(u32),
+    /// A guest memory mapping would overlap an existing one
+    MemoryRegionOverlap {
+        /// The existing memory slot
+        slot: u32,
+        /// Guest physical address of the existing mapping
+        guest_phys_addr: u64,
+    },
+    /// An argument was not valid for the current state
+    InvalidArgument(&'static str),
+}
+
+impl Error {
+    /// The error number reported by the kernel, if any
+    pub fn errno(&self) -> Option<i32> {
+        match *self {
+            Error::Open(ref e) |
+            Error::MapVcpu { error: ref e, .. } |
+            Error::EventFd(ref e) => e.raw_os_error(),
+            Error::Ioctl { errno, .. } |
+            Error::Vcpu { errno, .. } |
+            Error::CreateVcpu { errno, .. } |
+            Error::SetUserMemoryRegion { errno, .. } => Some(errno),
+            Error::ApiVersion(_) |
+            Error::TooManyVcpus { .. } |
+            Error::NoFreeMemorySlots { .. } |
+            Error::InvalidMemorySlot(_) |
+            Error::MemoryRegionOverlap { .. } |
+            Error::InvalidArgument(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Open(ref e) => {
+                write!(fmt, "failed to open /dev/kvm: {}", e)
+            }
+            Error::ApiVersion(v) => {
+                write!(fmt, "unexpected KVM API version {}", v)
+            }
+            Error::Ioctl { op, errno } => {
+                write!(fmt,
+                       "{} failed: {}",
+                       op,
+                       io::Error::from_raw_os_error(errno))
+            }
+            Error::Vcpu { id, op, errno } => {
+                write!(fmt,
+                       "{} failed on vcpu {}: {}",
+                       op,
+                       id,
+                       io::Error::from_raw_os_error(errno))
+            }
+            Error::CreateVcpu { id, errno } => {
+                write!(fmt,
+                       "KVM_CREATE_VCPU failed for vcpu {}: {}",
+                       id,
+                       io::Error::from_raw_os_error(errno))
+            }
+            Error::MapVcpu { id, ref error } => {
+                write!(fmt, "failed to map vcpu {}: {}", id, error)
+            }
+            Error::EventFd(ref e) => write!(fmt, "eventfd failed: {}", e),
+            Error::TooManyVcpus { max } => {
+                write!(fmt, "would exceed the maximum of {} vcpus", max)
+            }
+            Error::SetUserMemoryRegion { slot, guest_phys_addr, errno } => {
+                write!(fmt,
+                       "KVM_SET_USER_MEMORY_REGION failed for slot {} at \
+                        {:#x}: {}",
+                       slot,
+                       guest_phys_addr,
+                       io::Error::from_raw_os_error(errno))
+            }
+            Error::NoFreeMemorySlots { max } => {
+                write!(fmt, "all {} memory slots are in use", max)
+            }
+            Error::InvalidMemorySlot(slot) => {
+                write!(fmt, "memory slot {} is not in use", slot)
+            }
+            Error::MemoryRegionOverlap { slot, guest_phys_addr } => {
+                write!(fmt,
+                       "memory region overlaps slot {} at {:#x}",
+                       slot,
+                       guest_phys_addr)
+            }
+            Error::InvalidArgument(msg) => write!(fmt, "{}", msg),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Open(ref e) |
+            Error::MapVcpu { error: ref e, .. } |
+            Error::EventFd(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
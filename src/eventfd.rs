@@ -0,0 +1,96 @@
+// Copyright 2015 Dan Schatzberg.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A minimal wrapper around Linux eventfds.
+
+use libc::{self, EFD_CLOEXEC, EFD_NONBLOCK, c_void};
+use std::fs::File;
+use std::io;
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+use super::{Error, Result};
+
+/// An eventfd, a counter which can be signalled and waited on through a file
+/// descriptor.
+///
+/// KVM uses these to raise interrupts and report I/O without the threads
+/// involved having to hold a `Vcpu`.
+#[derive(Debug)]
+pub struct EventFd {
+    fd: File,
+}
+
+impl EventFd {
+    /// Create an eventfd whose reads block until it is signalled
+    pub fn new() -> Result<Self> {
+        EventFd::with_flags(EFD_CLOEXEC)
+    }
+
+    /// Create an eventfd whose reads fail with `EAGAIN` if it has not been
+    /// signalled
+    pub fn new_nonblocking() -> Result<Self> {
+        EventFd::with_flags(EFD_CLOEXEC | EFD_NONBLOCK)
+    }
+
+    fn with_flags(flags: i32) -> Result<Self> {
+        let ret = unsafe { libc::eventfd(0, flags) };
+        if ret < 0 {
+            return Err(Error::EventFd(io::Error::last_os_error()));
+        }
+        Ok(EventFd { fd: unsafe { File::from_raw_fd(ret) } })
+    }
+
+    /// Add `val` to the counter, waking any reader
+    pub fn write(&self, val: u64) -> Result<()> {
+        let ptr: *const u64 = &val;
+        let ret = unsafe {
+            libc::write(self.fd.as_raw_fd(),
+                        ptr as *const c_void,
+                        size_of::<u64>())
+        };
+        if ret < 0 {
+            return Err(Error::EventFd(io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    /// Wait for the counter to be non-zero, then return it and reset it to
+    /// zero
+    pub fn read(&self) -> Result<u64> {
+        let mut val = 0u64;
+        let ptr: *mut u64 = &mut val;
+        let ret = unsafe {
+            libc::read(self.fd.as_raw_fd(),
+                       ptr as *mut c_void,
+                       size_of::<u64>())
+        };
+        if ret < 0 {
+            return Err(Error::EventFd(io::Error::last_os_error()));
+        }
+        Ok(val)
+    }
+
+    /// Create a new handle to the same eventfd
+    pub fn try_clone(&self) -> Result<Self> {
+        let fd = self.fd.try_clone().map_err(Error::EventFd)?;
+        Ok(EventFd { fd })
+    }
+}
+
+impl AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
@@ -0,0 +1,314 @@
+// Copyright 2015 Dan Schatzberg.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A userspace IOAPIC for use with a split irqchip.
+
+use std::sync::{Arc, Mutex};
+
+use super::{Error, GsiRoutingTable, IoapicState, IrqStatus, RedirectionEntry,
+            Result, VirtualMachine};
+
+/// Default guest physical address of the IOAPIC registers
+pub const IOAPIC_DEFAULT_BASE: u64 = 0xfec00000;
+/// Number of interrupt pins of the IOAPIC
+pub const IOAPIC_NUM_PINS: u32 = 24;
+
+// Size of the register window
+const IOAPIC_MEM_LENGTH: u64 = 0x100;
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+
+const IOAPIC_REG_ID: u32 = 0x00;
+const IOAPIC_REG_VERSION: u32 = 0x01;
+const IOAPIC_REG_ARB: u32 = 0x02;
+const IOAPIC_REG_REDIRTBL: u32 = 0x10;
+const IOAPIC_REG_REDIRTBL_LAST: u32 =
+    IOAPIC_REG_REDIRTBL + 2 * IOAPIC_NUM_PINS - 1;
+// 82093AA, which has no EOI register
+const IOAPIC_VERSION: u32 = 0x11;
+
+const MSI_ADDRESS_BASE: u64 = 0xfee00000;
+const MSI_ADDRESS_DEST_SHIFT: u32 = 12;
+const MSI_ADDRESS_DEST_MODE_LOGICAL: u64 = 1 << 2;
+const MSI_DATA_DELIVERY_MODE_SHIFT: u32 = 8;
+const MSI_DATA_LEVEL_TRIGGERED: u32 = 1 << 15;
+
+#[derive(Debug)]
+struct IoapicInner {
+    state: IoapicState,
+    // Current level of each pin, used to detect edges
+    lines: u32,
+}
+
+/// An IOAPIC emulated in userspace which delivers interrupts as MSIs.
+///
+/// This is used after `VirtualMachine::enable_split_irqchip`, with the guest
+/// accessing its registers through `VcpuExit::MmioRead` and
+/// `VcpuExit::MmioWrite` exits. The state uses the same layout as the
+/// in-kernel IOAPIC, so it can be saved and restored in the same way.
+#[derive(Debug)]
+pub struct Ioapic {
+    vm: Arc<VirtualMachine>,
+    inner: Mutex<IoapicInner>,
+}
+
+impl Ioapic {
+    /// Create an IOAPIC in its reset state, with every pin masked
+    pub fn new(vm: &Arc<VirtualMachine>) -> Self {
+        let mut state = IoapicState {
+            base_address: IOAPIC_DEFAULT_BASE,
+            ..Default::default()
+        };
+        for entry in state.redirtbl.iter_mut() {
+            entry.set_masked(true);
+        }
+        Ioapic {
+            vm: vm.clone(),
+            inner: Mutex::new(IoapicInner {
+                state,
+                lines: 0,
+            }),
+        }
+    }
+
+    /// Guest physical address of the registers
+    pub fn base_address(&self) -> u64 {
+        self.inner.lock().unwrap().state.base_address
+    }
+
+    /// Check whether a guest physical address falls within the registers
+    pub fn contains(&self, addr: u64) -> bool {
+        self.inner.lock().unwrap().offset(addr).is_some()
+    }
+
+    /// Handle a guest read of the registers
+    ///
+    /// Reads outside of the registers return zero.
+    pub fn mmio_read(&self, addr: u64, data: &mut [u8]) {
+        let inner = self.inner.lock().unwrap();
+        let val = match inner.offset(addr) {
+            Some(IOREGSEL) => inner.state.ioregsel,
+            Some(IOWIN) => inner.read_register(),
+            _ => 0,
+        };
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = if i < 4 { (val >> (i * 8)) as u8 } else { 0 };
+        }
+    }
+
+    /// Handle a guest write to the registers
+    ///
+    /// Unmasking a pin with a pending interrupt delivers it. Returns whether
+    /// the MSI delivered by a pin changed, in which case the routes from
+    /// `add_msi_routes` must be installed again. Writes outside of the
+    /// registers are ignored.
+    pub fn mmio_write(&self, addr: u64, data: &[u8]) -> Result<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        let mut val = 0;
+        for (i, &b) in data.iter().take(4).enumerate() {
+            val |= (b as u32) << (i * 8);
+        }
+        match inner.offset(addr) {
+            Some(IOREGSEL) => inner.state.ioregsel = val & 0xff,
+            Some(IOWIN) => {
+                if let Some((pin, old)) = inner.write_register(val) {
+                    self.service(&mut inner, pin)?;
+                    return Ok(msi_message(&old) !=
+                              msi_message(&inner.entry(pin)));
+                }
+            }
+            _ => {}
+        }
+        Ok(false)
+    }
+
+    /// Set the level of an interrupt pin
+    ///
+    /// Edge triggered pins deliver an interrupt when raised. Level triggered
+    /// pins deliver one while raised, and again after each
+    /// `end_of_interrupt` for as long as they stay raised.
+    pub fn set_irq(&self, pin: u32, level: bool) -> Result<()> {
+        if pin >= IOAPIC_NUM_PINS {
+            return Err(Error::InvalidArgument("IOAPIC pin out of range"));
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let bit = 1 << pin;
+        let entry = inner.entry(pin);
+        let was_raised = inner.lines & bit != 0;
+        if level {
+            inner.lines |= bit;
+            if entry.level_triggered() || !was_raised {
+                inner.state.irr |= bit;
+                self.service(&mut inner, pin)?;
+            }
+        } else {
+            inner.lines &= !bit;
+            if entry.level_triggered() {
+                inner.state.irr &= !bit;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle the end of a level triggered interrupt.
+    ///
+    /// This should be called for `VcpuExit::IoapicEoi`. Pins using the vector
+    /// which are still raised deliver their interrupt again. For these exits
+    /// to occur, KVM must know that the vector belongs to a level triggered
+    /// interrupt of the IOAPIC, see `add_msi_routes`.
+    pub fn end_of_interrupt(&self, vector: u8) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        for pin in 0..IOAPIC_NUM_PINS {
+            let mut entry = inner.entry(pin);
+            if entry.vector() != vector || !entry.remote_irr() {
+                continue;
+            }
+            entry.set_remote_irr(false);
+            inner.set_entry(pin, entry);
+            self.service(&mut inner, pin)?;
+        }
+        Ok(())
+    }
+
+    /// Route the GSI of each pin to the MSI it currently delivers.
+    ///
+    /// KVM scans these routes to learn which vectors are used by level
+    /// triggered interrupts of the IOAPIC, and only reports
+    /// `VcpuExit::IoapicEoi` for those. The table should be rebuilt and
+    /// installed whenever `mmio_write` reports that the routes changed, and
+    /// after `set_state`.
+    pub fn add_msi_routes(&self, table: &mut GsiRoutingTable) {
+        let inner = self.inner.lock().unwrap();
+        for pin in 0..IOAPIC_NUM_PINS {
+            let (address, data) = msi_message(&inner.entry(pin));
+            table.add_msi(pin, address, data, None);
+        }
+    }
+
+    /// Get the state of the IOAPIC
+    pub fn state(&self) -> IoapicState {
+        self.inner.lock().unwrap().state
+    }
+
+    /// Set the state of the IOAPIC
+    ///
+    /// No interrupts are delivered until a pin next changes.
+    pub fn set_state(&self, state: &IoapicState) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.state = *state;
+        inner.lines = 0;
+    }
+
+    /// Deliver the pending interrupt on a pin, if it is not masked
+    fn service(&self, inner: &mut IoapicInner, pin: u32) -> Result<()> {
+        let mut entry = inner.entry(pin);
+        let level = entry.level_triggered();
+        if inner.state.irr & (1 << pin) == 0 || entry.masked() ||
+           (level && entry.remote_irr()) {
+            return Ok(());
+        }
+        let (address, data) = msi_message(&entry);
+        if self.vm.signal_msi(address, data, None)? == IrqStatus::Blocked {
+            // Stay pending until the entry or the pin next changes
+            return Ok(());
+        }
+        if level {
+            entry.set_remote_irr(true);
+            inner.set_entry(pin, entry);
+        } else {
+            inner.state.irr &= !(1 << pin);
+        }
+        Ok(())
+    }
+}
+
+/// The MSI address and data which deliver the interrupt of an entry
+fn msi_message(entry: &RedirectionEntry) -> (u64, u32) {
+    let mut address = MSI_ADDRESS_BASE |
+                      (entry.destination() as u64) << MSI_ADDRESS_DEST_SHIFT;
+    if entry.logical_destination() {
+        address |= MSI_ADDRESS_DEST_MODE_LOGICAL;
+    }
+    let mut data = entry.vector() as u32 |
+                   entry.delivery_mode() << MSI_DATA_DELIVERY_MODE_SHIFT;
+    if entry.level_triggered() {
+        data |= MSI_DATA_LEVEL_TRIGGERED;
+    }
+    (address, data)
+}
+
+impl IoapicInner {
+    /// The offset of a guest physical address within the registers
+    fn offset(&self, addr: u64) -> Option<u64> {
+        match addr.checked_sub(self.state.base_address) {
+            Some(offset) if offset < IOAPIC_MEM_LENGTH => Some(offset),
+            _ => None,
+        }
+    }
+
+    fn entry(&self, pin: u32) -> RedirectionEntry {
+        self.state.redirtbl[pin as usize]
+    }
+
+    fn set_entry(&mut self, pin: u32, entry: RedirectionEntry) {
+        self.state.redirtbl[pin as usize] = entry;
+    }
+
+    fn read_register(&self) -> u32 {
+        match self.state.ioregsel {
+            IOAPIC_REG_ID | IOAPIC_REG_ARB => self.state.id << 24,
+            IOAPIC_REG_VERSION => {
+                IOAPIC_VERSION | (IOAPIC_NUM_PINS - 1) << 16
+            }
+            reg @ IOAPIC_REG_REDIRTBL..=IOAPIC_REG_REDIRTBL_LAST => {
+                let index = reg - IOAPIC_REG_REDIRTBL;
+                let entry = self.entry(index / 2).0;
+                if index % 2 == 0 {
+                    entry as u32
+                } else {
+                    (entry >> 32) as u32
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    /// Write the selected register, returning the pin whose redirection
+    /// entry changed along with its previous entry
+    fn write_register(&mut self, val: u32) -> Option<(u32, RedirectionEntry)> {
+        match self.state.ioregsel {
+            IOAPIC_REG_ID => {
+                self.state.id = (val >> 24) & 0xf;
+                None
+            }
+            reg @ IOAPIC_REG_REDIRTBL..=IOAPIC_REG_REDIRTBL_LAST => {
+                let index = reg - IOAPIC_REG_REDIRTBL;
+                let pin = index / 2;
+                let old = self.entry(pin);
+                let mut entry = RedirectionEntry(if index % 2 == 0 {
+                    (old.0 & !0xffffffff) | val as u64
+                } else {
+                    (old.0 & 0xffffffff) | (val as u64) << 32
+                });
+                // The delivery status and remote IRR are read-only
+                entry.set_send_pending(old.send_pending());
+                entry.set_remote_irr(old.remote_irr() &&
+                                     entry.level_triggered());
+                self.set_entry(pin, entry);
+                Some((pin, old))
+            }
+            _ => None,
+        }
+    }
+}
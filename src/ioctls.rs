@@ -0,0 +1,207 @@
+// Copyright 2015 Dan Schatzberg.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! KVM ioctl request numbers and helpers to issue them.
+//!
+//! Request numbers are encoded the same way as the `_IO`, `_IOR`, `_IOW` and
+//! `_IOWR` macros in `<asm-generic/ioctl.h>`.
+
+use libc::{self, c_int, c_ulong, c_void};
+use std::mem::size_of;
+
+use super::{ClearDirtyLog, DirtyLog, EnableCap, Ioeventfd, IrqLevel,
+            IrqRouting, Irqfd, Msi, UserspaceMemoryRegion};
+#[cfg(target_arch = "x86_64")]
+use super::{Cpuid2, Fpu, Interrupt, Irqchip, LapicState, MsrList, Msrs,
+            PitConfig, PitState2, Regs, Sregs, VcpuEvents};
+
+const IOC_NRBITS: c_ulong = 8;
+const IOC_TYPEBITS: c_ulong = 8;
+const IOC_SIZEBITS: c_ulong = 14;
+
+const IOC_NRSHIFT: c_ulong = 0;
+const IOC_TYPESHIFT: c_ulong = IOC_NRSHIFT + IOC_NRBITS;
+const IOC_SIZESHIFT: c_ulong = IOC_TYPESHIFT + IOC_TYPEBITS;
+const IOC_DIRSHIFT: c_ulong = IOC_SIZESHIFT + IOC_SIZEBITS;
+
+const IOC_NONE: c_ulong = 0;
+const IOC_WRITE: c_ulong = 1;
+const IOC_READ: c_ulong = 2;
+
+const KVMIO: c_ulong = 0xae;
+
+const fn ioc(dir: c_ulong, nr: c_ulong, size: usize) -> c_ulong {
+    (dir << IOC_DIRSHIFT) | (KVMIO << IOC_TYPESHIFT) | (nr << IOC_NRSHIFT) |
+    ((size as c_ulong) << IOC_SIZESHIFT)
+}
+
+const fn io(nr: c_ulong) -> c_ulong {
+    ioc(IOC_NONE, nr, 0)
+}
+
+const fn ior(nr: c_ulong, size: usize) -> c_ulong {
+    ioc(IOC_READ, nr, size)
+}
+
+const fn iow(nr: c_ulong, size: usize) -> c_ulong {
+    ioc(IOC_WRITE, nr, size)
+}
+
+const fn iowr(nr: c_ulong, size: usize) -> c_ulong {
+    ioc(IOC_READ | IOC_WRITE, nr, size)
+}
+
+// ioctls for /dev/kvm
+pub const KVM_GET_API_VERSION: c_ulong = io(0x00);
+pub const KVM_CREATE_VM: c_ulong = io(0x01);
+#[cfg(target_arch = "x86_64")]
+pub const KVM_GET_MSR_INDEX_LIST: c_ulong = iowr(0x02, size_of::<MsrList>());
+pub const KVM_CHECK_EXTENSION: c_ulong = io(0x03);
+pub const KVM_GET_VCPU_MMAP_SIZE: c_ulong = io(0x04);
+#[cfg(target_arch = "x86_64")]
+pub const KVM_GET_SUPPORTED_CPUID: c_ulong = iowr(0x05, size_of::<Cpuid2>());
+#[cfg(target_arch = "x86_64")]
+pub const KVM_GET_MSR_FEATURE_INDEX_LIST: c_ulong =
+    iowr(0x0a, size_of::<MsrList>());
+
+// ioctls for VM fds
+pub const KVM_CREATE_VCPU: c_ulong = io(0x41);
+pub const KVM_GET_DIRTY_LOG: c_ulong = iow(0x42, size_of::<DirtyLog>());
+pub const KVM_SET_USER_MEMORY_REGION: c_ulong =
+    iow(0x46, size_of::<UserspaceMemoryRegion>());
+pub const KVM_CREATE_IRQCHIP: c_ulong = io(0x60);
+pub const KVM_IRQ_LINE: c_ulong = iow(0x61, size_of::<IrqLevel>());
+#[cfg(target_arch = "x86_64")]
+pub const KVM_GET_IRQCHIP: c_ulong = iowr(0x62, size_of::<Irqchip>());
+// Not a typo: KVM_SET_IRQCHIP is defined with _IOR
+#[cfg(target_arch = "x86_64")]
+pub const KVM_SET_IRQCHIP: c_ulong = ior(0x63, size_of::<Irqchip>());
+pub const KVM_IRQ_LINE_STATUS: c_ulong = iowr(0x67, size_of::<IrqLevel>());
+pub const KVM_SET_GSI_ROUTING: c_ulong = iow(0x6a, size_of::<IrqRouting>());
+// Takes a ReinjectControl despite being defined with _IO
+#[cfg(target_arch = "x86_64")]
+pub const KVM_REINJECT_CONTROL: c_ulong = io(0x71);
+pub const KVM_IRQFD: c_ulong = iow(0x76, size_of::<Irqfd>());
+#[cfg(target_arch = "x86_64")]
+pub const KVM_CREATE_PIT2: c_ulong = iow(0x77, size_of::<PitConfig>());
+pub const KVM_IOEVENTFD: c_ulong = iow(0x79, size_of::<Ioeventfd>());
+#[cfg(target_arch = "x86_64")]
+pub const KVM_GET_PIT2: c_ulong = ior(0x9f, size_of::<PitState2>());
+#[cfg(target_arch = "x86_64")]
+pub const KVM_SET_PIT2: c_ulong = iow(0xa0, size_of::<PitState2>());
+pub const KVM_ENABLE_CAP: c_ulong = iow(0xa3, size_of::<EnableCap>());
+pub const KVM_SIGNAL_MSI: c_ulong = iow(0xa5, size_of::<Msi>());
+pub const KVM_CLEAR_DIRTY_LOG: c_ulong =
+    iowr(0xc0, size_of::<ClearDirtyLog>());
+pub const KVM_RESET_DIRTY_RINGS: c_ulong = io(0xc7);
+
+// ioctls for vcpu fds
+pub const KVM_RUN: c_ulong = io(0x80);
+#[cfg(target_arch = "x86_64")]
+pub const KVM_GET_REGS: c_ulong = ior(0x81, size_of::<Regs>());
+#[cfg(target_arch = "x86_64")]
+pub const KVM_SET_REGS: c_ulong = iow(0x82, size_of::<Regs>());
+#[cfg(target_arch = "x86_64")]
+pub const KVM_GET_SREGS: c_ulong = ior(0x83, size_of::<Sregs>());
+#[cfg(target_arch = "x86_64")]
+pub const KVM_SET_SREGS: c_ulong = iow(0x84, size_of::<Sregs>());
+#[cfg(target_arch = "x86_64")]
+pub const KVM_INTERRUPT: c_ulong = iow(0x86, size_of::<Interrupt>());
+#[cfg(target_arch = "x86_64")]
+pub const KVM_GET_MSRS: c_ulong = iowr(0x88, size_of::<Msrs>());
+#[cfg(target_arch = "x86_64")]
+pub const KVM_SET_MSRS: c_ulong = iow(0x89, size_of::<Msrs>());
+#[cfg(target_arch = "x86_64")]
+pub const KVM_GET_FPU: c_ulong = ior(0x8c, size_of::<Fpu>());
+#[cfg(target_arch = "x86_64")]
+pub const KVM_SET_FPU: c_ulong = iow(0x8d, size_of::<Fpu>());
+#[cfg(target_arch = "x86_64")]
+pub const KVM_GET_LAPIC: c_ulong = ior(0x8e, size_of::<LapicState>());
+#[cfg(target_arch = "x86_64")]
+pub const KVM_SET_LAPIC: c_ulong = iow(0x8f, size_of::<LapicState>());
+#[cfg(target_arch = "x86_64")]
+pub const KVM_SET_CPUID2: c_ulong = iow(0x90, size_of::<Cpuid2>());
+#[cfg(target_arch = "x86_64")]
+pub const KVM_NMI: c_ulong = io(0x9a);
+#[cfg(target_arch = "x86_64")]
+pub const KVM_GET_VCPU_EVENTS: c_ulong = ior(0x9f, size_of::<VcpuEvents>());
+#[cfg(target_arch = "x86_64")]
+pub const KVM_SET_VCPU_EVENTS: c_ulong = iow(0xa0, size_of::<VcpuEvents>());
+#[cfg(target_arch = "x86_64")]
+pub const KVM_SMI: c_ulong = io(0xb7);
+
+/// Issue an ioctl which takes no argument
+pub unsafe fn ioctl(fd: c_int, req: c_ulong) -> c_int {
+    libc::ioctl(fd, req, 0)
+}
+
+/// Issue an ioctl whose argument is passed by value
+pub unsafe fn ioctl_with_val(fd: c_int, req: c_ulong, arg: c_ulong) -> c_int {
+    libc::ioctl(fd, req, arg)
+}
+
+/// Issue an ioctl which reads from `arg`
+pub unsafe fn ioctl_with_ref<T>(fd: c_int, req: c_ulong, arg: &T) -> c_int {
+    let ptr: *const T = arg;
+    libc::ioctl(fd, req, ptr as *const c_void)
+}
+
+/// Issue an ioctl which may write to `arg`
+pub unsafe fn ioctl_with_mut_ref<T>(fd: c_int,
+                                    req: c_ulong,
+                                    arg: &mut T)
+                                    -> c_int {
+    let ptr: *mut T = arg;
+    libc::ioctl(fd, req, ptr as *mut c_void)
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+#[allow(clippy::assertions_on_constants)]
+fn request_number_test() {
+    assert!(KVM_GET_API_VERSION == 0xae00);
+    assert!(KVM_RUN == 0xae80);
+    assert!(KVM_GET_SUPPORTED_CPUID == 0xc008ae05);
+    assert!(KVM_SET_USER_MEMORY_REGION == 0x4020ae46);
+    assert!(KVM_GET_DIRTY_LOG == 0x4010ae42);
+    assert!(KVM_ENABLE_CAP == 0x4068aea3);
+    assert!(KVM_CLEAR_DIRTY_LOG == 0xc018aec0);
+    assert!(KVM_RESET_DIRTY_RINGS == 0xaec7);
+    assert!(KVM_GET_REGS == 0x8090ae81);
+    assert!(KVM_SET_SREGS == 0x4138ae84);
+    assert!(KVM_GET_FPU == 0x81a0ae8c);
+    assert!(KVM_GET_MSR_INDEX_LIST == 0xc004ae02);
+    assert!(KVM_GET_MSR_FEATURE_INDEX_LIST == 0xc004ae0a);
+    assert!(KVM_GET_MSRS == 0xc008ae88);
+    assert!(KVM_SET_MSRS == 0x4008ae89);
+    assert!(KVM_GET_LAPIC == 0x8400ae8e);
+    assert!(KVM_INTERRUPT == 0x4004ae86);
+    assert!(KVM_NMI == 0xae9a);
+    assert!(KVM_SMI == 0xaeb7);
+    assert!(KVM_GET_VCPU_EVENTS == 0x8040ae9f);
+    assert!(KVM_SET_VCPU_EVENTS == 0x4040aea0);
+    assert!(KVM_CREATE_IRQCHIP == 0xae60);
+    assert!(KVM_IRQ_LINE == 0x4008ae61);
+    assert!(KVM_GET_IRQCHIP == 0xc208ae62);
+    assert!(KVM_SET_IRQCHIP == 0x8208ae63);
+    assert!(KVM_IRQ_LINE_STATUS == 0xc008ae67);
+    assert!(KVM_SIGNAL_MSI == 0x4020aea5);
+    assert!(KVM_REINJECT_CONTROL == 0xae71);
+    assert!(KVM_CREATE_PIT2 == 0x4040ae77);
+    assert!(KVM_IRQFD == 0x4020ae76);
+    assert!(KVM_IOEVENTFD == 0x4040ae79);
+    assert!(KVM_SET_GSI_ROUTING == 0x4008ae6a);
+    assert!(KVM_GET_PIT2 == 0x8070ae9f);
+    assert!(KVM_SET_PIT2 == 0x4070aea0);
+}
@@ -33,38 +33,60 @@ extern crate memmap;
 #[allow(missing_docs, missing_debug_implementations)]
 mod x86_64;
 
+mod error;
+mod eventfd;
+mod ioctls;
+#[cfg(target_arch = "x86_64")]
+mod ioapic;
+
 #[cfg(target_arch = "x86_64")]
 pub use self::x86_64::*;
 
+pub use self::error::{Error, Operation, Result};
+pub use self::eventfd::EventFd;
+#[cfg(target_arch = "x86_64")]
+pub use self::ioapic::{IOAPIC_DEFAULT_BASE, IOAPIC_NUM_PINS, Ioapic};
+
 use errno::{Errno, errno};
-use libc::{E2BIG, ENOMEM, c_int};
+use libc::{E2BIG, ENOMEM, c_int, c_ulong};
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt;
 use std::fs::{File, OpenOptions};
-use std::io::{self, Error, ErrorKind};
-use std::mem;
-use std::ops::DerefMut;
+use std::iter;
+use std::mem::size_of;
+use std::ops::{Deref, DerefMut};
 use std::os::unix::io::{AsRawFd, FromRawFd};
-
+use std::ptr;
+use std::slice;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{self, AtomicBool, AtomicUsize, Ordering};
 use memmap::{Mmap, Protection};
 
+use ioctls::*;
+
 const API_VERSION: i32 = 12;
 
-extern {
-    fn kvm_get_api_version(fd: c_int) -> c_int;
-    fn kvm_create_vm(fd: c_int, flags: c_int) -> c_int;
-    fn kvm_check_extension(fd: c_int, extension: c_int) -> c_int;
-    fn kvm_get_vcpu_mmap_size(fd: c_int) -> c_int;
-    fn kvm_get_supported_cpuid(fd: c_int, cpuid: *mut Cpuid2) -> c_int;
-    fn kvm_create_vcpu(fd: c_int, vcpu_id: c_int) -> c_int;
-    fn kvm_set_user_memory_region(fd: c_int,
-                                  region: *const UserspaceMemoryRegion)
-                                  -> c_int;
-    fn kvm_run(fd: c_int) -> c_int;
-    fn kvm_get_regs(fd: c_int, regs: *mut Regs) -> c_int;
-    fn kvm_set_regs(fd: c_int, regs: *const Regs) -> c_int;
-    fn kvm_get_sregs(fd: c_int, sregs: *mut Sregs) -> c_int;
-    fn kvm_set_sregs(fd: c_int, sregs: *const Sregs) -> c_int;
-    fn kvm_set_cpuid2(fd: c_int, cpuid: *const Cpuid2) -> c_int;
+/// Size of a host page
+fn page_size() -> u64 {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 }
+}
+
+/// Take ownership of the file descriptor returned by an ioctl, or `None` if
+/// the ioctl failed
+unsafe fn file_from_ioctl(ret: c_int) -> Option<File> {
+    if ret < 0 {
+        None
+    } else {
+        Some(File::from_raw_fd(ret))
+    }
+}
+
+fn ioctl_error(op: Operation) -> Error {
+    Error::Ioctl {
+        op,
+        errno: errno().0,
+    }
 }
 
 /// Handle to the KVM system.
@@ -73,24 +95,29 @@ extern {
 /// its capabilities.
 #[derive(Debug)]
 pub struct System {
-    fd: File,
+    fd: Arc<File>,
 }
 
 /// A Virtual Machine.
 ///
-/// This allows the creation of `Vcpu`s and establishing memory mappings
+/// This allows the creation of `Vcpu`s and establishing memory mappings.
+/// A `VirtualMachine` is shared through an `Arc` with the `Vcpu`s created on
+/// it. All operations take `&self`, so it can still be configured while the
+/// `Vcpu`s run on other threads.
 #[derive(Debug)]
-pub struct VirtualMachine<'a> {
+pub struct VirtualMachine {
     fd: File,
-    sys: &'a System,
-    mem_slots: Vec<&'a mut [u8]>,
-    num_vcpus: u32,
+    sys: System,
+    mem_slots: Mutex<BTreeMap<u32, MemorySlot>>,
+    vcpu_ids: Mutex<BTreeSet<u32>>,
+    // Number of entries in each dirty ring, or 0 if the rings are disabled.
+    // Only changed while holding the vcpu_ids lock.
+    dirty_ring_entries: AtomicUsize,
+    #[cfg(target_arch = "x86_64")]
+    x2apic_ids: AtomicBool,
     check_extension: bool,
 }
 
-/// Result type used by this crate
-pub type Result<T> = io::Result<T>;
-
 /// KVM system capabilities
 #[allow(missing_docs)]
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
@@ -115,14 +142,225 @@ pub enum Capability {
     IoMmu = 18,
     DestroyMemoryRegionWorks = 21,
     UserNmi,
-    MaxVcpus = 66,
-    CheckExtensionVm = 105,
+    SetGuestDebug,
+    ReinjectControl,
+    IrqRouting,
+    IrqInjectStatus,
+    AssignDevIrq = 29,
+    JoinMemoryRegionsWorks,
+    Mce,
+    Irqfd,
+    Pit2,
+    SetBootCpuId,
+    PitState2,
+    Ioeventfd,
+    SetIdentityMapAddr,
+    XenHvm,
+    AdjustClock,
+    InternalErrorData,
+    VcpuEvents,
+    S390Psw,
+    PpcSegstate,
+    Hyperv,
+    HypervVapic,
+    HypervSpin,
+    PciSegment,
+    PpcPairedSingles,
+    IntrShadow,
+    Debugregs,
+    X86RobustSinglestep,
+    PpcOsi,
+    PpcUnsetIrq,
+    EnableCap,
+    Xsave,
+    Xcrs,
+    PpcGetPvinfo,
+    PpcIrqLevel,
+    AsyncPf,
+    TscControl,
+    GetTscKhz,
+    PpcBookeSregs,
+    SpaprTce,
+    PpcSmt,
+    PpcRma,
+    MaxVcpus,
+    PpcHior,
+    PpcPapr,
+    SwTlb,
+    OneReg,
+    S390Gmap,
+    TscDeadlineTimer,
+    S390Ucontrol,
+    SyncRegs,
+    Pci2_3,
+    KvmclockCtrl,
+    SignalMsi,
+    PpcGetSmmuInfo,
+    S390Cow,
+    PpcAllocHtab,
+    ReadonlyMem,
+    IrqfdResample,
+    PpcBookeWatchdog,
+    PpcHtabFd,
+    S390CssSupport,
+    PpcEpr,
+    ArmPsci,
+    ArmSetDeviceAddr,
+    DeviceCtrl,
+    IrqMpic,
+    PpcRtas,
+    IrqXics,
+    ArmEl1_32bit,
+    SpaprMultitce,
+    ExtEmulCpuid,
+    HypervTime,
+    IoapicPolarityIgnored,
+    EnableCapVm,
+    S390Irqchip,
+    IoeventfdNoLength,
+    VmAttributes,
+    ArmPsci0_2,
+    PpcFixupHcall,
+    PpcEnableHcall,
+    CheckExtensionVm,
+    S390UserSigp,
+    S390VectorRegisters,
+    S390MemOp,
+    S390UserStsi,
+    S390Skeys,
+    MipsFpu,
+    MipsMsa,
+    S390InjectIrq,
+    S390IrqState,
+    PpcHwrng,
+    DisableQuirks,
+    X86Smm,
+    MultiAddressSpace,
+    GuestDebugHwBps,
+    GuestDebugHwWps,
+    SplitIrqchip,
+    IoeventfdAnyLength,
+    HypervSynic,
+    S390Ri,
+    SpaprTce64,
+    ArmPmuV3,
+    VcpuAttributes,
+    MaxVcpuId,
+    X2apicApi,
+    S390UserInstr0,
+    MsiDevid,
+    PpcHtm,
+    SpaprResizeHpt,
+    PpcMmuRadix,
+    PpcMmuHashV3,
+    ImmediateExit,
+    MipsVz,
+    MipsTe,
+    Mips64bit,
+    S390Gs,
+    S390Ais,
+    SpaprTceVfio,
+    X86DisableExits,
+    ArmUserIrq,
+    S390CmmaMigration,
+    PpcFwnmi,
+    PpcSmtPossible,
+    HypervSynic2,
+    HypervVpIndex,
+    S390AisMigration,
+    PpcGetCpuChar,
+    S390Bpb,
+    GetMsrFeatures,
+    HypervEventfd,
+    HypervTlbflush,
+    S390Hpage1m,
+    NestedState,
+    ArmInjectSerrorEsr,
+    MsrPlatformInfo,
+    PpcNestedHv,
+    HypervSendIpi,
+    CoalescedPio,
+    HypervEnlightenedVmcs,
+    ExceptionPayload,
+    ArmVmIpaSize,
+    ManualDirtyLogProtect,
+    HypervCpuid,
+    ManualDirtyLogProtect2,
+    PpcIrqXive,
+    ArmSve,
+    ArmPtrauthAddress,
+    ArmPtrauthGeneric,
+    PmuEventFilter,
+    ArmIrqLineLayout2,
+    HypervDirectTlbflush,
+    PpcGuestDebugSstep,
+    ArmNisvToUser,
+    ArmInjectExtDabt,
+    S390VcpuResets,
+    S390Protected,
+    PpcSecureGuest,
+    HaltPoll,
+    AsyncPfInt,
+    LastCpu,
+    SmallerMaxphyaddr,
+    S390Diag318,
+    StealTime,
+    X86UserSpaceMsr,
+    X86MsrFilter,
+    EnforcePvFeatureCpuid,
+    SysHypervCpuid,
+    DirtyLogRing,
+    X86BusLockExit,
+    PpcDawr1,
+    SetGuestDebug2,
+    SgxAttribute,
+    VmCopyEncContextFrom,
+    PtpKvm,
+    HypervEnforceCpuid,
+    Sregs2,
+    ExitHypercall,
+    PpcRptInvalidate,
+    BinaryStatsFd,
+    ExitOnEmulationFailure,
+    ArmMte,
+    VmMoveEncContextFrom,
+    VmGpaBits,
+    Xsave2,
+    SysAttributes,
+    PpcAilMode3,
+    S390MemOpExtension,
+    PmuCapability,
+    DisableQuirks2,
+    VmTscControl,
+    SystemEventData,
+    ArmSystemSuspend,
+    S390ProtectedDump,
+    X86TripleFaultEvent,
+    X86NotifyVmexit,
+    VmDisableNxHugePages,
+    S390ZpciOp,
+    S390CpuTopology,
+    DirtyLogRingAcqRel,
+    S390ProtectedAsyncDisable = 224,
+    DirtyLogRingWithBitmap = 225,
+    PmuEventMaskedEvents = 226,
+    CounterOffset = 227,
+    ArmEagerSplitChunkSize = 228,
+    ArmSupportedBlockSizes = 229,
+    ArmSupportedRegMaskRanges = 230,
+    UserMemory2 = 231,
+    MemoryFaultInfo = 232,
+    MemoryAttributes = 233,
+    GuestMemfd = 234,
+    VmTypes = 235,
+    PreFaultMemory = 236,
+    X86ApicBusCyclesNs = 237,
+    X86GuestMode = 238,
 }
 
 /// KVM `run` exit reasons
 #[allow(missing_docs)]
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
-#[repr(u32)]
 pub enum Exit {
     Unknown,
     Exception,
@@ -149,6 +387,266 @@ pub enum Exit {
     S390Tsch,
     Epr,
     SystemEvent,
+    S390Stsi,
+    IoapicEoi,
+    Hyperv,
+    ArmNisv,
+    X86Rdmsr,
+    X86Wrmsr,
+    DirtyRingFull,
+    ApResetHold,
+    X86BusLock,
+    Xen,
+    RiscvSbi,
+    RiscvCsr,
+    Notify,
+    LoongarchIocsr,
+    MemoryFault,
+    Tdx,
+    /// An exit reason not known to this crate
+    Unrecognized(u32),
+}
+
+impl From<u32> for Exit {
+    fn from(reason: u32) -> Exit {
+        match reason {
+            0 => Exit::Unknown,
+            1 => Exit::Exception,
+            2 => Exit::Io,
+            3 => Exit::Hypercall,
+            4 => Exit::Debug,
+            5 => Exit::Hlt,
+            6 => Exit::Mmio,
+            7 => Exit::IrqWindowOpen,
+            8 => Exit::Shutdown,
+            9 => Exit::FailEntry,
+            10 => Exit::Intr,
+            11 => Exit::SetTpr,
+            12 => Exit::TprAccess,
+            13 => Exit::S390Sieic,
+            14 => Exit::S390Reset,
+            15 => Exit::Dcr,
+            16 => Exit::Nmi,
+            17 => Exit::InternalError,
+            18 => Exit::Osi,
+            19 => Exit::PaprHcall,
+            20 => Exit::S390Ucontrol,
+            21 => Exit::Watchdog,
+            22 => Exit::S390Tsch,
+            23 => Exit::Epr,
+            24 => Exit::SystemEvent,
+            25 => Exit::S390Stsi,
+            26 => Exit::IoapicEoi,
+            27 => Exit::Hyperv,
+            28 => Exit::ArmNisv,
+            29 => Exit::X86Rdmsr,
+            30 => Exit::X86Wrmsr,
+            31 => Exit::DirtyRingFull,
+            32 => Exit::ApResetHold,
+            33 => Exit::X86BusLock,
+            34 => Exit::Xen,
+            35 => Exit::RiscvSbi,
+            36 => Exit::RiscvCsr,
+            37 => Exit::Notify,
+            38 => Exit::LoongarchIocsr,
+            39 => Exit::MemoryFault,
+            40 => Exit::Tdx,
+            _ => Exit::Unrecognized(reason),
+        }
+    }
+}
+
+/// The guest requested a shutdown
+pub const SYSTEM_EVENT_SHUTDOWN: u32 = 1;
+/// The guest requested a reset
+pub const SYSTEM_EVENT_RESET: u32 = 2;
+/// The guest crashed
+pub const SYSTEM_EVENT_CRASH: u32 = 3;
+
+/// The reason `Vcpu::run` returned, along with its decoded payload.
+///
+/// Payloads are borrowed from the `Vcpu`'s shared run page. Where the guest
+/// expects userspace to provide a result (e.g. the data for an `in`
+/// instruction or an MMIO read) the payload is borrowed mutably and whatever
+/// is written to it is seen by the guest when the `Vcpu` is next run.
+#[derive(Debug)]
+pub enum VcpuExit<'a> {
+    /// Exit for an unknown reason
+    Unknown {
+        /// Architecture specific hardware exit reason
+        hardware_exit_reason: u64,
+    },
+    /// The guest raised an exception
+    Exception {
+        /// Exception vector
+        exception: u32,
+        /// Exception error code
+        error_code: u32,
+    },
+    /// The guest executed an `in` instruction
+    IoIn {
+        /// Port being read
+        port: u16,
+        /// Size of each access in bytes
+        size: u8,
+        /// Buffer to be filled with the data read by the guest
+        data: &'a mut [u8],
+    },
+    /// The guest executed an `out` instruction
+    IoOut {
+        /// Port being written
+        port: u16,
+        /// Size of each access in bytes
+        size: u8,
+        /// Data written by the guest
+        data: &'a [u8],
+    },
+    /// The guest made a hypercall
+    Hypercall {
+        /// Hypercall number
+        nr: u64,
+        /// Hypercall arguments
+        args: [u64; 6],
+        /// Value returned to the guest
+        ret: &'a mut u64,
+        /// Whether the guest was in long mode
+        longmode: bool,
+    },
+    /// A debug exit
+    Debug(DebugExitArch),
+    /// The guest executed a `hlt` instruction
+    Hlt,
+    /// The guest read from memory which is not backed by a memory region
+    MmioRead {
+        /// Guest physical address being read
+        addr: u64,
+        /// Buffer to be filled with the data read by the guest
+        data: &'a mut [u8],
+    },
+    /// The guest wrote to memory which is not backed by a memory region
+    MmioWrite {
+        /// Guest physical address being written
+        addr: u64,
+        /// Data written by the guest
+        data: &'a [u8],
+    },
+    /// The guest is ready for an interrupt to be injected, following
+    /// `Vcpu::set_request_interrupt_window`
+    IrqWindowOpen,
+    /// The guest triple faulted
+    Shutdown,
+    /// The `Vcpu` could not be entered
+    FailEntry {
+        /// Architecture specific hardware entry failure reason
+        hardware_entry_failure_reason: u64,
+    },
+    /// A signal is pending
+    Intr,
+    /// The guest set the task priority register
+    SetTpr,
+    /// The guest accessed the task priority register
+    TprAccess {
+        /// Instruction pointer of the access
+        rip: u64,
+        /// Whether the access was a write
+        is_write: bool,
+    },
+    /// An s390 SIE intercept
+    S390Sieic {
+        /// Intercept code
+        icptcode: u8,
+        /// Instruction parameter A
+        ipa: u16,
+        /// Instruction parameter B
+        ipb: u32,
+    },
+    /// An s390 reset request
+    S390Reset {
+        /// Reset flags
+        flags: u64,
+    },
+    /// A PowerPC device control register access
+    Dcr {
+        /// Device control register number
+        dcrn: u32,
+        /// Data written by the guest, or to be returned on a read
+        data: &'a mut u32,
+        /// Whether the access was a write
+        is_write: bool,
+    },
+    /// A non-maskable interrupt
+    Nmi,
+    /// KVM encountered an internal error
+    InternalError {
+        /// Error sub-code
+        suberror: u32,
+        /// Additional error information
+        data: &'a [u64],
+    },
+    /// A PowerPC OSI call
+    Osi {
+        /// Guest general purpose registers
+        gprs: &'a mut [u64; 32],
+    },
+    /// A PowerPC PAPR hypercall
+    PaprHcall {
+        /// Hypercall number
+        nr: u64,
+        /// Value returned to the guest
+        ret: &'a mut u64,
+        /// Hypercall arguments
+        args: [u64; 9],
+    },
+    /// An s390 user-controlled virtual machine translation fault
+    S390Ucontrol {
+        /// Translation exception code
+        trans_exc_code: u64,
+        /// Program interruption code
+        pgm_code: u32,
+    },
+    /// A watchdog timer expired
+    Watchdog,
+    /// An s390 TEST SUBCHANNEL intercept
+    S390Tsch {
+        /// Subchannel id
+        subchannel_id: u16,
+        /// Subchannel number
+        subchannel_nr: u16,
+        /// I/O interruption parameter
+        io_int_parm: u32,
+        /// I/O interruption word
+        io_int_word: u32,
+        /// Instruction parameter B
+        ipb: u32,
+        /// Whether an I/O interrupt was dequeued
+        dequeued: bool,
+    },
+    /// A PowerPC external proxy interrupt acknowledge
+    Epr {
+        /// Interrupt vector to be returned to the guest
+        epr: &'a mut u32,
+    },
+    /// The guest triggered a system level event
+    SystemEvent {
+        /// One of the `SYSTEM_EVENT_*` constants
+        kind: u32,
+        /// Architecture specific flags
+        flags: u64,
+    },
+    /// The guest signalled the end of a level triggered interrupt which was
+    /// delivered by a userspace IOAPIC, see `Ioapic::end_of_interrupt`
+    IoapicEoi {
+        /// Vector of the interrupt
+        vector: u8,
+    },
+    /// The dirty ring of the `Vcpu` is full.
+    ///
+    /// The dirty rings must be harvested and reset before the `Vcpu` is run
+    /// again.
+    DirtyRingFull,
+    /// An exit which this crate does not decode, or whose payload was not
+    /// understood
+    Unsupported(Exit),
 }
 
 #[repr(C)]
@@ -161,16 +659,253 @@ struct UserspaceMemoryRegion {
     pub userspace_addr: u64,
 }
 
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct IrqLevel {
+    // The status of KVM_IRQ_LINE_STATUS is returned in place of the irq
+    pub irq: u32,
+    pub level: u32,
+}
+
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Irqchip {
+    pub chip_id: u32,
+    pad: u32,
+    // Large enough for any of the chip states, aligned for IoapicState
+    pub chip: [u64; 64],
+}
+
+/// Result of changing an interrupt line with `set_irq_line_status` or
+/// injecting an MSI with `signal_msi`
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum IrqStatus {
+    /// The interrupt was delivered to this many destinations
+    Delivered(u32),
+    /// The interrupt was coalesced with one which was still pending
+    Coalesced,
+    /// The interrupt was masked or otherwise ignored
+    Masked,
+    /// No destination accepted the MSI, for example because the guest has
+    /// disabled its local APICs
+    Blocked,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct Msi {
+    pub address_lo: u32,
+    pub address_hi: u32,
+    pub data: u32,
+    pub flags: u32,
+    pub devid: u32,
+    pad: [u8; 12],
+}
+
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct Interrupt {
+    pub irq: u32,
+}
+
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct PitConfig {
+    pub flags: u32,
+    pad: [u32; 15],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct Irqfd {
+    pub fd: u32,
+    pub gsi: u32,
+    pub flags: u32,
+    pub resamplefd: u32,
+    pad: [u8; 16],
+}
+
+const IRQFD_FLAG_DEASSIGN: u32 = 1;
+const IRQFD_FLAG_RESAMPLE: u32 = 1 << 1;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Ioeventfd {
+    pub datamatch: u64,
+    pub addr: u64,
+    pub len: u32,
+    pub fd: i32,
+    pub flags: u32,
+    pad: [u8; 36],
+}
+
+const IOEVENTFD_FLAG_DATAMATCH: u32 = 1;
+const IOEVENTFD_FLAG_PIO: u32 = 1 << 1;
+const IOEVENTFD_FLAG_DEASSIGN: u32 = 1 << 2;
+
+/// Address of a guest write which signals an `EventFd`
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum IoEventAddress {
+    /// An I/O port
+    Pio(u16),
+    /// A guest physical address
+    Mmio(u64),
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct IrqRoutingEntry {
+    pub gsi: u32,
+    pub kind: u32,
+    pub flags: u32,
+    pad: u32,
+    // Either an irqchip and pin or an MSI address, data and device id
+    pub u: [u32; 8],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct IrqRouting {
+    pub nr: u32,
+    pub flags: u32,
+}
+
+const IRQ_ROUTING_IRQCHIP: u32 = 1;
+const IRQ_ROUTING_MSI: u32 = 2;
+const MSI_VALID_DEVID: u32 = 1;
+
+/// A table mapping GSIs to interrupt controller pins and MSIs.
+///
+/// The table is built up and then installed with
+/// `VirtualMachine::set_gsi_routing`, replacing the previous table.
+#[derive(Clone, Debug, Default)]
+pub struct GsiRoutingTable {
+    entries: Vec<IrqRoutingEntry>,
+}
+
+impl GsiRoutingTable {
+    /// Create an empty table
+    pub fn new() -> Self {
+        GsiRoutingTable::default()
+    }
+
+    /// Route a GSI to a pin of an in-kernel interrupt controller
+    ///
+    /// A GSI may be routed to pins on several interrupt controllers.
+    #[cfg(target_arch = "x86_64")]
+    pub fn add_irqchip(&mut self,
+                       gsi: u32,
+                       irqchip: IrqchipId,
+                       pin: u32)
+                       -> &mut Self {
+        let mut entry = IrqRoutingEntry {
+            gsi,
+            kind: IRQ_ROUTING_IRQCHIP,
+            ..Default::default()
+        };
+        entry.u[0] = irqchip as u32;
+        entry.u[1] = pin;
+        self.entries.push(entry);
+        self
+    }
+
+    /// Route the GSIs of the PICs and the IOAPIC to their pins, as KVM does
+    /// when the irqchip is created
+    #[cfg(target_arch = "x86_64")]
+    pub fn add_default_irqchip_routes(&mut self) -> &mut Self {
+        for gsi in 0..IOAPIC_NUM_PINS {
+            if gsi < 8 {
+                self.add_irqchip(gsi, IrqchipId::PicMaster, gsi);
+            } else if gsi < 16 {
+                self.add_irqchip(gsi, IrqchipId::PicSlave, gsi - 8);
+            }
+            self.add_irqchip(gsi, IrqchipId::Ioapic, gsi);
+        }
+        self
+    }
+
+    /// Route a GSI to an MSI
+    ///
+    /// The device id is required by some interrupt controllers, see
+    /// `Capability::MsiDevid`.
+    pub fn add_msi(&mut self,
+                   gsi: u32,
+                   address: u64,
+                   data: u32,
+                   devid: Option<u32>)
+                   -> &mut Self {
+        let mut entry = IrqRoutingEntry {
+            gsi,
+            kind: IRQ_ROUTING_MSI,
+            ..Default::default()
+        };
+        entry.u[0] = address as u32;
+        entry.u[1] = (address >> 32) as u32;
+        entry.u[2] = data;
+        if let Some(devid) = devid {
+            entry.flags = MSI_VALID_DEVID;
+            entry.u[3] = devid;
+        }
+        self.entries.push(entry);
+        self
+    }
+
+    /// Number of routes in the table
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check whether the table has no routes
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct DirtyLog {
+    pub slot: u32,
+    padding1: u32,
+    pub dirty_bitmap: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct ClearDirtyLog {
+    pub slot: u32,
+    pub num_pages: u32,
+    pub first_page: u64,
+    pub dirty_bitmap: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct EnableCap {
+    pub cap: u32,
+    pub flags: u32,
+    pub args: [u64; 4],
+    pad: [u8; 64],
+}
+
 /// A Virtual CPU.
-pub struct Vcpu<'a> {
+///
+/// A `Vcpu` is `Send`, so each one may be moved to its own thread.
+pub struct Vcpu {
+    id: u32,
     fd: File,
-    vm: &'a VirtualMachine<'a>,
+    vm: Arc<VirtualMachine>,
     mmap: Mmap,
+    dirty_ring: Option<Mmap>,
+    dirty_ring_index: u32,
 }
 
-impl<'a> fmt::Debug for Vcpu<'a> {
+impl fmt::Debug for Vcpu {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         fmt.debug_struct("Vcpu")
+           .field("id", &self.id)
            .field("fd", &self.fd)
            .field("vm", &self.vm)
            .finish()
@@ -182,10 +917,10 @@ impl<'a> fmt::Debug for Vcpu<'a> {
 #[allow(missing_docs)]
 #[repr(C)]
 #[derive(Copy)]
-pub struct Run {
+struct Run {
     request_interrupt_window: u8,
     padding1: [u8; 7usize],
-    pub exit_reason: Exit,
+    pub exit_reason: u32,
     pub ready_for_interrupt_injection: u8,
     pub if_flag: u8,
     pub flags: u16,
@@ -197,8 +932,11 @@ pub struct Run {
     pub s: Union_Unnamed26,
 }
 
-#[allow(missing_docs)]
+#[allow(dead_code, clippy::useless_transmute)]
 impl Run {
+    pub fn reason(&self) -> Exit {
+        Exit::from(self.exit_reason)
+    }
     pub fn hw(&self) -> *const Struct_Unnamed9 {
         unsafe {
             let raw: *mut u8 = ::std::mem::transmute(&self._bindgen_data_1_);
@@ -415,6 +1153,12 @@ impl Run {
             ::std::mem::transmute(raw.offset(0))
         }
     }
+    pub fn eoi(&self) -> *const ExitIoapicEoi {
+        unsafe {
+            let raw: *mut u8 = ::std::mem::transmute(&self._bindgen_data_1_);
+            ::std::mem::transmute(raw.offset(0))
+        }
+    }
 }
 impl ::std::clone::Clone for Run {
     fn clone(&self) -> Self {
@@ -431,14 +1175,14 @@ impl fmt::Debug for Run {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         let mut s = fmt.debug_struct("Run");
         s.field("request_interrupt_window", &self.request_interrupt_window)
-         .field("exit_reason", &self.exit_reason)
+         .field("exit_reason", &self.reason())
          .field("ready_for_interrupt_injection",
                 &self.ready_for_interrupt_injection)
          .field("if_flag", &self.if_flag)
          .field("flags", &self.flags)
          .field("cr8", &self.cr8);
         unsafe {
-            match self.exit_reason {
+            match self.reason() {
                 Exit::Unknown => s.field("hw", &*self.hw()),
                 Exit::FailEntry => s.field("fail_entry", &*self.fail_entry()),
                 Exit::Exception => s.field("ex", &*self.ex()),
@@ -459,6 +1203,7 @@ impl fmt::Debug for Run {
                 Exit::Epr => s.field("epr", &*self.epr()),
                 Exit::SystemEvent =>
                     s.field("system_event", &*self.system_event()),
+                Exit::IoapicEoi => s.field("eoi", &*self.eoi()),
                 _ => &mut s,
             }
         }
@@ -468,7 +1213,7 @@ impl fmt::Debug for Run {
 #[allow(missing_docs)]
 #[repr(C)]
 #[derive(Copy, Debug)]
-pub struct Struct_Unnamed9 {
+struct Struct_Unnamed9 {
     pub hardware_exit_reason: u64,
 }
 impl ::std::clone::Clone for Struct_Unnamed9 {
@@ -484,7 +1229,7 @@ impl ::std::default::Default for Struct_Unnamed9 {
 #[allow(missing_docs)]
 #[repr(C)]
 #[derive(Copy, Debug)]
-pub struct Struct_Unnamed10 {
+struct Struct_Unnamed10 {
     pub hardware_entry_failure_reason: u64,
 }
 impl ::std::clone::Clone for Struct_Unnamed10 {
@@ -500,7 +1245,7 @@ impl ::std::default::Default for Struct_Unnamed10 {
 #[allow(missing_docs)]
 #[repr(C)]
 #[derive(Copy, Debug)]
-pub struct Struct_Unnamed11 {
+struct Struct_Unnamed11 {
     pub exception: u32,
     pub error_code: u32,
 }
@@ -515,19 +1260,14 @@ impl ::std::default::Default for Struct_Unnamed11 {
     }
 }
 
-#[allow(missing_docs)]
-#[repr(u8)]
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
-pub enum IoDirection {
-    In,
-    Out,
-}
+const IO_IN: u8 = 0;
+const IO_OUT: u8 = 1;
 
 #[allow(missing_docs)]
 #[repr(C)]
 #[derive(Copy, Debug)]
-pub struct ExitIo {
-    pub direction: IoDirection,
+struct ExitIo {
+    pub direction: u8,
     pub size: u8,
     pub port: u16,
     pub count: u32,
@@ -543,10 +1283,15 @@ impl ::std::default::Default for ExitIo {
         unsafe { ::std::mem::zeroed() }
     }
 }
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct ExitIoapicEoi {
+    pub vector: u8,
+}
 #[allow(missing_docs)]
 #[repr(C)]
 #[derive(Copy, Debug)]
-pub struct Struct_Unnamed13 {
+struct Struct_Unnamed13 {
     pub arch: DebugExitArch,
 }
 impl ::std::clone::Clone for Struct_Unnamed13 {
@@ -562,7 +1307,7 @@ impl ::std::default::Default for Struct_Unnamed13 {
 #[allow(missing_docs)]
 #[repr(C)]
 #[derive(Copy, Debug)]
-pub struct Struct_Unnamed14 {
+struct Struct_Unnamed14 {
     pub phys_addr: u64,
     pub data: [u8; 8usize],
     pub len: u32,
@@ -581,7 +1326,7 @@ impl ::std::default::Default for Struct_Unnamed14 {
 #[allow(missing_docs)]
 #[repr(C)]
 #[derive(Copy, Debug)]
-pub struct Struct_Unnamed15 {
+struct Struct_Unnamed15 {
     pub nr: u64,
     pub args: [u64; 6usize],
     pub ret: u64,
@@ -601,7 +1346,7 @@ impl ::std::default::Default for Struct_Unnamed15 {
 #[allow(missing_docs)]
 #[repr(C)]
 #[derive(Copy, Debug)]
-pub struct Struct_Unnamed16 {
+struct Struct_Unnamed16 {
     pub rip: u64,
     pub is_write: u32,
     pub pad: u32,
@@ -619,7 +1364,7 @@ impl ::std::default::Default for Struct_Unnamed16 {
 #[allow(missing_docs)]
 #[repr(C)]
 #[derive(Copy, Debug)]
-pub struct Struct_Unnamed17 {
+struct Struct_Unnamed17 {
     pub icptcode: u8,
     pub ipa: u16,
     pub ipb: u32,
@@ -637,7 +1382,7 @@ impl ::std::default::Default for Struct_Unnamed17 {
 #[allow(missing_docs)]
 #[repr(C)]
 #[derive(Copy, Debug)]
-pub struct Struct_Unnamed18 {
+struct Struct_Unnamed18 {
     pub trans_exc_code: u64,
     pub pgm_code: u32,
 }
@@ -654,7 +1399,7 @@ impl ::std::default::Default for Struct_Unnamed18 {
 #[allow(missing_docs)]
 #[repr(C)]
 #[derive(Copy, Debug)]
-pub struct Struct_Unnamed19 {
+struct Struct_Unnamed19 {
     pub dcrn: u32,
     pub data: u32,
     pub is_write: u8,
@@ -672,7 +1417,7 @@ impl ::std::default::Default for Struct_Unnamed19 {
 #[allow(missing_docs)]
 #[repr(C)]
 #[derive(Copy, Debug)]
-pub struct Struct_Unnamed20 {
+struct Struct_Unnamed20 {
     pub suberror: u32,
     pub ndata: u32,
     pub data: [u64; 16usize],
@@ -690,7 +1435,7 @@ impl ::std::default::Default for Struct_Unnamed20 {
 #[allow(missing_docs)]
 #[repr(C)]
 #[derive(Copy, Debug)]
-pub struct Struct_Unnamed21 {
+struct Struct_Unnamed21 {
     pub gprs: [u64; 32usize],
 }
 impl ::std::clone::Clone for Struct_Unnamed21 {
@@ -706,7 +1451,7 @@ impl ::std::default::Default for Struct_Unnamed21 {
 #[allow(missing_docs)]
 #[repr(C)]
 #[derive(Copy, Debug)]
-pub struct Struct_Unnamed22 {
+struct Struct_Unnamed22 {
     pub nr: u64,
     pub ret: u64,
     pub args: [u64; 9usize],
@@ -724,7 +1469,7 @@ impl ::std::default::Default for Struct_Unnamed22 {
 #[allow(missing_docs)]
 #[repr(C)]
 #[derive(Copy, Debug)]
-pub struct Struct_Unnamed23 {
+struct Struct_Unnamed23 {
     pub subchannel_id: u16,
     pub subchannel_nr: u16,
     pub io_int_parm: u32,
@@ -745,7 +1490,7 @@ impl ::std::default::Default for Struct_Unnamed23 {
 #[allow(missing_docs)]
 #[repr(C)]
 #[derive(Copy, Debug)]
-pub struct Struct_Unnamed24 {
+struct Struct_Unnamed24 {
     pub epr: u32,
 }
 impl ::std::clone::Clone for Struct_Unnamed24 {
@@ -761,7 +1506,7 @@ impl ::std::default::Default for Struct_Unnamed24 {
 #[allow(missing_docs)]
 #[repr(C)]
 #[derive(Copy, Debug)]
-pub struct Struct_Unnamed25 {
+struct Struct_Unnamed25 {
     pub _type: u32,
     pub flags: u64,
 }
@@ -778,10 +1523,10 @@ impl ::std::default::Default for Struct_Unnamed25 {
 #[allow(missing_docs, missing_debug_implementations)]
 #[repr(C)]
 #[derive(Copy)]
-pub struct Union_Unnamed26 {
+struct Union_Unnamed26 {
     pub _bindgen_data_: [u8; 1024usize],
 }
-#[allow(missing_docs)]
+#[allow(dead_code, clippy::useless_transmute)]
 impl Union_Unnamed26 {
     pub fn regs(&self) -> *const SyncRegs {
         unsafe {
@@ -810,15 +1555,16 @@ impl ::std::default::Default for Union_Unnamed26 {
 impl System {
     /// Initialize the KVM system
     pub fn initialize() -> Result<Self> {
-        let f = try!(OpenOptions::new()
-                         .read(true)
-                         .write(true)
-                         .open("/dev/kvm"));
-        let vers = unsafe { kvm_get_api_version(f.as_raw_fd()) };
+        let f = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open("/dev/kvm")
+                    .map_err(Error::Open)?;
+        let vers = unsafe { ioctl(f.as_raw_fd(), KVM_GET_API_VERSION) };
         if vers == API_VERSION {
-            Ok(System { fd: f })
+            Ok(System { fd: Arc::new(f) })
         } else {
-            Err(Error::new(ErrorKind::NotFound, "Unexpected API Version"))
+            Err(Error::ApiVersion(vers))
         }
     }
 
@@ -828,7 +1574,11 @@ impl System {
     /// than the `System` since `VirtualMachine`s may have different
     /// capabilities
     pub fn check_capability(&self, cap: Capability) -> i32 {
-        unsafe { kvm_check_extension(self.fd.as_raw_fd(), cap as c_int) }
+        unsafe {
+            ioctl_with_val(self.fd.as_raw_fd(),
+                           KVM_CHECK_EXTENSION,
+                           cap as c_ulong)
+        }
     }
 
     /// Recommended maximum number of `Vcpu`s
@@ -853,15 +1603,33 @@ impl System {
         }
     }
 
-    fn get_vcpu_mmap_size(&self) -> usize {
-        let ret = unsafe { kvm_get_vcpu_mmap_size(self.fd.as_raw_fd()) };
-        assert!(ret > 0 && ret as usize >= mem::size_of::<Run>());
-        ret as usize
+    /// Upper bound (exclusive) on the ids which may be given to `Vcpu`s
+    pub fn max_vcpu_id(&self) -> u32 {
+        let r = self.check_capability(Capability::MaxVcpuId);
+        if r != 0 {
+            r as u32
+        } else {
+            self.max_vcpus()
+        }
+    }
+
+    fn get_vcpu_mmap_size(&self) -> Result<usize> {
+        let ret =
+            unsafe { ioctl(self.fd.as_raw_fd(), KVM_GET_VCPU_MMAP_SIZE) };
+        if ret < 0 {
+            return Err(ioctl_error(Operation::GetVcpuMmapSize));
+        }
+        assert!(ret as usize >= size_of::<Run>());
+        Ok(ret as usize)
     }
 }
 
 #[cfg(target_arch = "x86_64")]
 const CPUID_ENTRIES: u32 = 64;
+#[cfg(target_arch = "x86_64")]
+const MSR_INDICES: u32 = 256;
+#[cfg(target_arch = "x86_64")]
+const APICBASE_X2APIC_ENABLE: u64 = 1 << 10;
 
 #[cfg(target_arch = "x86_64")]
 impl System {
@@ -871,7 +1639,9 @@ impl System {
         loop {
             let mut c = CpuidHandle::new(nent);
             let err = unsafe {
-                kvm_get_supported_cpuid(self.fd.as_raw_fd(), c.deref_mut())
+                ioctl_with_mut_ref(self.fd.as_raw_fd(),
+                                   KVM_GET_SUPPORTED_CPUID,
+                                   c.deref_mut())
             };
             if err != 0 {
                 if errno() == Errno(E2BIG) {
@@ -881,206 +1651,1685 @@ impl System {
                     nent = c.nent;
                     continue;
                 } else {
-                    return Err(Error::last_os_error());
+                    return Err(ioctl_error(Operation::GetSupportedCpuid));
                 }
             } else {
                 return Ok(c);
             }
         }
     }
-}
-
-/// Instruct KVM to keep track of writes to memory within the slot
-pub const MEM_LOG_DIRTY_PAGES: u32 = 1;
-/// If `Capability::ReadonlyMem`, make this mapping read-only
-pub const MEM_READONLY: u32 = 1 << 1;
-
-impl<'a> VirtualMachine<'a> {
-    /// Create a `VirtualMachine`
-    pub fn create(s: &'a System) -> Result<Self> {
-        let f = unsafe { kvm_create_vm(s.fd.as_raw_fd(), 0) };
-        if f == -1 {
-            return Err(Error::last_os_error());
-        }
 
-        let check_extension =
-            s.check_capability(Capability::CheckExtensionVm) != 0;
-        Ok(VirtualMachine {
-            fd: unsafe { File::from_raw_fd(f) },
-            sys: s,
-            mem_slots: Vec::new(),
-            num_vcpus: 0,
-            check_extension: check_extension,
-        })
+    /// Get the MSRs which can be read and written on a `Vcpu`
+    pub fn get_msr_index_list(&self) -> Result<Vec<u32>> {
+        self.get_msr_list(KVM_GET_MSR_INDEX_LIST, Operation::GetMsrIndexList)
     }
 
-    /// Check for a capability on this `VirtualMachine`
-    pub fn check_capability(&mut self, cap: Capability) -> i32 {
-        if self.check_extension {
-            unsafe { kvm_check_extension(self.fd.as_raw_fd(), cap as c_int) }
-        } else {
-            self.sys.check_capability(cap)
-        }
+    /// Get the MSRs which describe features supported by KVM and this host
+    ///
+    /// This requires `Capability::GetMsrFeatures`.
+    pub fn get_feature_msr_index_list(&self) -> Result<Vec<u32>> {
+        self.get_msr_list(KVM_GET_MSR_FEATURE_INDEX_LIST,
+                          Operation::GetMsrFeatureIndexList)
     }
 
-    /// Establish a guest memory mapping.
+    /// Get the values of feature MSRs
     ///
-    /// The slice specified by `user_addr` is mapped at `phys_addr`. Flags is
-    /// the bitwise or of `MEM_LOG_DIRTY_PAGES` and/or `MEM_READONLY`.
-    pub fn set_user_memory_region(&mut self,
-                                  phys_addr: u64,
-                                  user_addr: &'a mut [u8],
-                                  flags: u32)
-                                  -> Result<()> {
-        let slot = self.mem_slots.len();
-        let region = UserspaceMemoryRegion {
-            slot: slot as u32,
-            flags: flags,
-            guest_phys_addr: phys_addr,
-            memory_size: user_addr.len() as u64,
-            userspace_addr: user_addr.as_mut_ptr() as u64,
-        };
+    /// The indices should be taken from `get_feature_msr_index_list`. Only
+    /// the entries up to the first MSR which could not be read are returned.
+    pub fn get_msr_features(&self, indices: &[u32]) -> Result<MsrHandle> {
+        let mut msrs = MsrHandle::from_indices(indices);
         let ret = unsafe {
-            kvm_set_user_memory_region(self.fd.as_raw_fd(), &region)
+            ioctl_with_mut_ref(self.fd.as_raw_fd(),
+                               KVM_GET_MSRS,
+                               msrs.deref_mut())
         };
-        if ret == 0 {
-            self.mem_slots.push(user_addr);
-            Ok(())
-        } else {
-            Err(Error::new(ErrorKind::Other, "Unknown Error"))
+        if ret < 0 {
+            return Err(ioctl_error(Operation::GetMsrFeatures));
         }
+        msrs.nmsrs = ret as u32;
+        Ok(msrs)
     }
-}
 
-impl<'a> Vcpu<'a> {
-    /// Create a `Vcpu` on the specified `VirtualMachine`
-    pub fn create(vm: &'a mut VirtualMachine<'a>) -> Result<Self> {
-        if vm.num_vcpus >= vm.sys.max_vcpus() {
-            return Err(Error::new(ErrorKind::AlreadyExists,
-                                  "Would exceed max_vcpus"));
-        } else if vm.num_vcpus >= vm.sys.recommended_vcpus() {
-            warn!("Exceeding recommended_vcpus");
+    fn get_msr_list(&self, req: c_ulong, op: Operation) -> Result<Vec<u32>> {
+        let mut nmsrs = MSR_INDICES;
+        loop {
+            // The indices follow the count
+            let mut buf = vec![0u32; nmsrs as usize + 1];
+            buf[0] = nmsrs;
+            let ret = unsafe {
+                let list = &mut *(buf.as_mut_ptr() as *mut MsrList);
+                ioctl_with_mut_ref(self.fd.as_raw_fd(), req, list)
+            };
+            if ret != 0 {
+                if errno() == Errno(E2BIG) {
+                    // The kernel reports how many indices it has
+                    nmsrs = buf[0];
+                    continue;
+                } else {
+                    return Err(ioctl_error(op));
+                }
+            }
+            let n = buf[0] as usize;
+            buf.truncate(n + 1);
+            buf.remove(0);
+            return Ok(buf);
         }
-        let fd = unsafe {
-            File::from_raw_fd(kvm_create_vcpu(vm.fd.as_raw_fd(),
-                                              vm.num_vcpus as c_int))
-        };
-        vm.num_vcpus += 1;
-        let mmap_size = vm.sys.get_vcpu_mmap_size();
-        let m = try!(Mmap::open_with_offset(&fd,
-                                            Protection::ReadWrite,
-                                            0,
-                                            mmap_size));
-        Ok(Vcpu {
-            fd: fd,
-            vm: vm,
-            mmap: m,
-        })
     }
+}
 
-    /// Run the `Vcpu`
-    pub unsafe fn run(&mut self) -> Result<Run> {
-        let ret = kvm_run(self.fd.as_raw_fd());
-        if ret == 0 {
-            Ok(*(self.mmap.mut_ptr() as *mut Run))
-        } else {
-            Err(Error::last_os_error())
-        }
-    }
+struct MemorySlot {
+    guest_phys_addr: u64,
+    flags: u32,
+    memory: Mmap,
+}
 
-    /// Get registers
-    pub fn get_regs(&self) -> Result<Regs> {
-        let mut regs = Regs::default();
-        let ret = unsafe { kvm_get_regs(self.fd.as_raw_fd(), &mut regs) };
-        if ret == 0 {
-            Ok(regs)
-        } else {
-            Err(Error::last_os_error())
-        }
+impl fmt::Debug for MemorySlot {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("MemorySlot")
+           .field("guest_phys_addr", &self.guest_phys_addr)
+           .field("flags", &self.flags)
+           .field("memory_size", &self.memory.len())
+           .finish()
     }
+}
 
-    /// Set registers
-    pub fn set_regs(&mut self, regs: &Regs) -> Result<()> {
-        let ret = unsafe { kvm_set_regs(self.fd.as_raw_fd(), regs) };
-        if ret == 0 {
-            Ok(())
-        } else {
-            Err(Error::last_os_error())
+impl MemorySlot {
+    fn region(&mut self,
+              slot: u32,
+              flags: u32,
+              guest_phys_addr: u64)
+              -> UserspaceMemoryRegion {
+        UserspaceMemoryRegion {
+            slot,
+            flags,
+            guest_phys_addr,
+            memory_size: self.memory.len() as u64,
+            userspace_addr: self.memory.mut_ptr() as u64,
         }
     }
 }
 
-#[cfg(target_arch = "x86_64")]
-impl<'a> Vcpu<'a> {
-    /// Set the response to the CPUID instruction
-    pub fn set_cpuid2(&mut self, cpuid: &mut Cpuid2) -> Result<()> {
-        let ptr: *mut Cpuid2 = cpuid;
-        let ret = unsafe { kvm_set_cpuid2(self.fd.as_raw_fd(), ptr) };
-        if ret == 0 {
-            Ok(())
-        } else {
-            Err(Error::last_os_error())
+/// Check that `[phys_addr, phys_addr + len)` does not overlap any slot other
+/// than `exclude`
+fn check_overlap(mem_slots: &BTreeMap<u32, MemorySlot>,
+                 exclude: Option<u32>,
+                 phys_addr: u64,
+                 len: usize)
+                 -> Result<()> {
+    let end = match phys_addr.checked_add(len as u64) {
+        Some(end) => end,
+        None => {
+            return Err(Error::InvalidArgument("Memory region wraps around \
+                                               the guest physical address \
+                                               space"))
         }
-    }
-    /// Get special registers
-    pub fn get_sregs(&self) -> Result<Sregs> {
-        let mut sregs = Sregs::default();
-        let ret = unsafe { kvm_get_sregs(self.fd.as_raw_fd(), &mut sregs) };
-        if ret == 0 {
-            Ok(sregs)
-        } else {
-            Err(Error::last_os_error())
+    };
+    for (&slot, mem_slot) in mem_slots {
+        if Some(slot) == exclude {
+            continue;
         }
-    }
-    /// Set special registers
-    pub fn set_sregs(&mut self, sregs: &Sregs) -> Result<()> {
-        let ret = unsafe { kvm_set_sregs(self.fd.as_raw_fd(), sregs) };
-        if ret == 0 {
-            Ok(())
-        } else {
-            Err(Error::last_os_error())
+        let slot_end = mem_slot.guest_phys_addr + mem_slot.memory.len() as u64;
+        if phys_addr < slot_end && mem_slot.guest_phys_addr < end {
+            return Err(Error::MemoryRegionOverlap {
+                slot,
+                guest_phys_addr: mem_slot.guest_phys_addr,
+            });
         }
     }
+    Ok(())
 }
 
-#[test]
-fn init_test() {
-    System::initialize().unwrap();
-}
+/// Instruct KVM to keep track of writes to memory within the slot
+pub const MEM_LOG_DIRTY_PAGES: u32 = 1;
+/// If `Capability::ReadonlyMem`, make this mapping read-only
+pub const MEM_READONLY: u32 = 1 << 1;
 
-#[test]
-fn check_capability_test() {
-    let h = System::initialize().unwrap();
-    assert!(h.check_capability(Capability::UserMemory) != 0);
-}
+const DIRTY_LOG_MANUAL_PROTECT_ENABLE: u64 = 1;
+const DIRTY_LOG_INITIALLY_SET: u64 = 1 << 1;
 
-#[test]
-fn vcpus_test() {
-    let h = System::initialize().unwrap();
-    assert!(h.max_vcpus() >= h.recommended_vcpus());
+/// Pages of a memory slot which were written by the guest.
+///
+/// Pages are identified by their index within the memory slot, so page `n`
+/// is at guest physical address `guest_phys_addr() + n * page_size()`.
+#[derive(Clone, Debug)]
+pub struct DirtyBitmap {
+    slot: u32,
+    guest_phys_addr: u64,
+    page_size: u64,
+    num_pages: u64,
+    bits: Vec<u64>,
 }
 
-#[test]
-fn create_vm_test() {
-    let h = System::initialize().unwrap();
-    VirtualMachine::create(&h).unwrap();
-}
+impl DirtyBitmap {
+    /// The memory slot this bitmap was retrieved from
+    pub fn slot(&self) -> u32 {
+        self.slot
+    }
 
-#[test]
-fn set_memory_test() {
-    let mut anon_mmap = Mmap::anonymous(16 * (1 << 12), Protection::ReadWrite)
-                            .unwrap();
-    let slice = unsafe { anon_mmap.as_mut_slice() };
+    /// Guest physical address of the start of the memory slot
+    pub fn guest_phys_addr(&self) -> u64 {
+        self.guest_phys_addr
+    }
+
+    /// Size of the pages tracked by the bitmap, which is the host page size
+    pub fn page_size(&self) -> u64 {
+        self.page_size
+    }
+
+    /// Number of pages in the memory slot
+    pub fn num_pages(&self) -> u64 {
+        self.num_pages
+    }
+
+    /// Check whether a page was written
+    pub fn is_dirty(&self, page: u64) -> bool {
+        page < self.num_pages &&
+        self.bits[(page / 64) as usize] & (1 << (page % 64)) != 0
+    }
+
+    /// Number of pages which were written
+    pub fn count(&self) -> u64 {
+        self.bits.iter().map(|w| w.count_ones() as u64).sum()
+    }
+
+    /// The raw bitmap, one bit per page
+    pub fn as_slice(&self) -> &[u64] {
+        &self.bits
+    }
+
+    /// Iterate over the indices of pages which were written
+    pub fn pages(&self) -> DirtyPages<'_> {
+        DirtyPages::new(&self.bits, 0, 1)
+    }
+
+    /// Iterate over the guest physical addresses of pages which were written
+    pub fn addrs(&self) -> DirtyPages<'_> {
+        DirtyPages::new(&self.bits, self.guest_phys_addr, self.page_size)
+    }
+}
+
+// Offset of the dirty ring in the Vcpu mapping, in pages
+#[cfg(target_arch = "x86_64")]
+const DIRTY_LOG_PAGE_OFFSET: usize = 64;
+#[cfg(not(target_arch = "x86_64"))]
+const DIRTY_LOG_PAGE_OFFSET: usize = 0;
+
+const DIRTY_GFN_F_DIRTY: u32 = 1;
+const DIRTY_GFN_F_RESET: u32 = 1 << 1;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct DirtyGfn {
+    flags: u32,
+    slot: u32,
+    offset: u64,
+}
+
+/// Iterator which harvests the dirty ring of a `Vcpu`
+#[derive(Debug)]
+pub struct DirtyRingEntries<'a> {
+    ring: *mut DirtyGfn,
+    count: u32,
+    index: &'a mut u32,
+}
+
+impl<'a> Iterator for DirtyRingEntries<'a> {
+    type Item = (u32, u64);
+
+    fn next(&mut self) -> Option<(u32, u64)> {
+        if self.count == 0 {
+            return None;
+        }
+        let i = *self.index & (self.count - 1);
+        unsafe {
+            let entry = self.ring.offset(i as isize);
+            if ptr::read_volatile(&(*entry).flags) & DIRTY_GFN_F_DIRTY == 0 {
+                return None;
+            }
+            // The kernel publishes the entry before setting the dirty flag
+            atomic::fence(Ordering::Acquire);
+            let item = ((*entry).slot, (*entry).offset);
+            atomic::fence(Ordering::Release);
+            ptr::write_volatile(&mut (*entry).flags, DIRTY_GFN_F_RESET);
+            *self.index = self.index.wrapping_add(1);
+            Some(item)
+        }
+    }
+}
+
+/// Iterator over the pages set in a `DirtyBitmap`
+#[derive(Clone, Debug)]
+pub struct DirtyPages<'a> {
+    words: iter::Enumerate<slice::Iter<'a, u64>>,
+    index: u64,
+    word: u64,
+    base: u64,
+    scale: u64,
+}
+
+impl<'a> DirtyPages<'a> {
+    fn new(bits: &'a [u64], base: u64, scale: u64) -> Self {
+        DirtyPages {
+            words: bits.iter().enumerate(),
+            index: 0,
+            word: 0,
+            base,
+            scale,
+        }
+    }
+}
+
+impl<'a> Iterator for DirtyPages<'a> {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        while self.word == 0 {
+            match self.words.next() {
+                Some((index, &word)) => {
+                    self.index = index as u64;
+                    self.word = word;
+                }
+                None => return None,
+            }
+        }
+        let page = self.index * 64 + self.word.trailing_zeros() as u64;
+        // Clear the lowest set bit
+        self.word &= self.word - 1;
+        Some(self.base + page * self.scale)
+    }
+}
+
+impl VirtualMachine {
+    /// Create a `VirtualMachine`
+    pub fn create(s: &System) -> Result<Arc<Self>> {
+        let ret =
+            unsafe { ioctl_with_val(s.fd.as_raw_fd(), KVM_CREATE_VM, 0) };
+        let fd = match unsafe { file_from_ioctl(ret) } {
+            Some(fd) => fd,
+            None => return Err(ioctl_error(Operation::CreateVm)),
+        };
+
+        let check_extension =
+            s.check_capability(Capability::CheckExtensionVm) != 0;
+        Ok(Arc::new(VirtualMachine {
+            fd,
+            sys: System { fd: s.fd.clone() },
+            mem_slots: Mutex::new(BTreeMap::new()),
+            vcpu_ids: Mutex::new(BTreeSet::new()),
+            dirty_ring_entries: AtomicUsize::new(0),
+            #[cfg(target_arch = "x86_64")]
+            x2apic_ids: AtomicBool::new(false),
+            check_extension,
+        }))
+    }
+
+    /// Check for a capability on this `VirtualMachine`
+    pub fn check_capability(&self, cap: Capability) -> i32 {
+        if self.check_extension {
+            unsafe {
+                ioctl_with_val(self.fd.as_raw_fd(),
+                               KVM_CHECK_EXTENSION,
+                               cap as c_ulong)
+            }
+        } else {
+            self.sys.check_capability(cap)
+        }
+    }
+
+    /// Maximum number of memory slots
+    pub fn max_mem_slots(&self) -> u32 {
+        let r = self.check_capability(Capability::NrMemSlots);
+        if r > 0 {
+            r as u32
+        } else {
+            // Older kernels without KVM_CAP_NR_MEMSLOTS provide 32 slots
+            32
+        }
+    }
+
+    /// Establish a guest memory mapping.
+    ///
+    /// The `VirtualMachine` takes ownership of `memory`, which is mapped at
+    /// `phys_addr`. Flags is the bitwise or of `MEM_LOG_DIRTY_PAGES` and/or
+    /// `MEM_READONLY`. Returns the memory slot used, which is the lowest one
+    /// not in use.
+    pub fn set_user_memory_region(&self,
+                                  phys_addr: u64,
+                                  mut memory: Mmap,
+                                  flags: u32)
+                                  -> Result<u32> {
+        if memory.len() == 0 {
+            return Err(Error::InvalidArgument("Memory region is empty"));
+        }
+        let mut mem_slots = self.mem_slots.lock().unwrap();
+        let slot = (0..).find(|slot| !mem_slots.contains_key(slot)).unwrap();
+        let max_mem_slots = self.max_mem_slots();
+        if slot >= max_mem_slots {
+            return Err(Error::NoFreeMemorySlots { max: max_mem_slots });
+        }
+        check_overlap(&mem_slots, None, phys_addr, memory.len())?;
+        let region = UserspaceMemoryRegion {
+            slot,
+            flags,
+            guest_phys_addr: phys_addr,
+            memory_size: memory.len() as u64,
+            userspace_addr: memory.mut_ptr() as u64,
+        };
+        self.set_memory_region_raw(&region)?;
+        mem_slots.insert(slot,
+                         MemorySlot {
+                             guest_phys_addr: phys_addr,
+                             flags,
+                             memory,
+                         });
+        Ok(slot)
+    }
+
+    /// Remove a guest memory mapping.
+    ///
+    /// The slot becomes free for reuse and the memory which backed it is
+    /// returned.
+    pub fn remove_memory_region(&self, slot: u32) -> Result<Mmap> {
+        let mut mem_slots = self.mem_slots.lock().unwrap();
+        let phys_addr = match mem_slots.get(&slot) {
+            Some(mem_slot) => mem_slot.guest_phys_addr,
+            None => return Err(Error::InvalidMemorySlot(slot)),
+        };
+        // A memory size of 0 deletes the slot
+        let region = UserspaceMemoryRegion {
+            slot,
+            flags: 0,
+            guest_phys_addr: phys_addr,
+            memory_size: 0,
+            userspace_addr: 0,
+        };
+        self.set_memory_region_raw(&region)?;
+        Ok(mem_slots.remove(&slot).unwrap().memory)
+    }
+
+    /// Change the flags of an existing guest memory mapping.
+    ///
+    /// This can be used to start or stop dirty page logging on the slot.
+    pub fn modify_memory_region_flags(&self,
+                                      slot: u32,
+                                      flags: u32)
+                                      -> Result<()> {
+        let mut mem_slots = self.mem_slots.lock().unwrap();
+        let mem_slot = match mem_slots.get_mut(&slot) {
+            Some(mem_slot) => mem_slot,
+            None => return Err(Error::InvalidMemorySlot(slot)),
+        };
+        let region = mem_slot.region(slot, flags, mem_slot.guest_phys_addr);
+        self.set_memory_region_raw(&region)?;
+        mem_slot.flags = flags;
+        Ok(())
+    }
+
+    /// Move an existing guest memory mapping to a new guest physical address.
+    ///
+    /// To resize a mapping, remove it and establish a new one.
+    pub fn move_memory_region(&self, slot: u32, phys_addr: u64) -> Result<()> {
+        let mut mem_slots = self.mem_slots.lock().unwrap();
+        let len = match mem_slots.get(&slot) {
+            Some(mem_slot) => mem_slot.memory.len(),
+            None => return Err(Error::InvalidMemorySlot(slot)),
+        };
+        check_overlap(&mem_slots, Some(slot), phys_addr, len)?;
+        let mem_slot = mem_slots.get_mut(&slot).unwrap();
+        let region = mem_slot.region(slot, mem_slot.flags, phys_addr);
+        self.set_memory_region_raw(&region)?;
+        mem_slot.guest_phys_addr = phys_addr;
+        Ok(())
+    }
+
+    /// Retrieve the pages written since the last call for a memory slot.
+    ///
+    /// The slot must have been established with `MEM_LOG_DIRTY_PAGES`, and
+    /// the dirty rings must not be enabled. Unless manual protection has been
+    /// enabled with `enable_manual_dirty_log_protect`, the returned pages are
+    /// write protected again so that later writes are reported by the next
+    /// call.
+    pub fn get_dirty_log(&self, slot: u32) -> Result<DirtyBitmap> {
+        let mem_slots = self.mem_slots.lock().unwrap();
+        let mem_slot = match mem_slots.get(&slot) {
+            Some(mem_slot) => mem_slot,
+            None => return Err(Error::InvalidMemorySlot(slot)),
+        };
+        if mem_slot.flags & MEM_LOG_DIRTY_PAGES == 0 {
+            return Err(Error::InvalidArgument("Dirty page logging is not \
+                                               enabled on the memory slot"));
+        }
+        if self.dirty_ring_entries.load(Ordering::Relaxed) != 0 {
+            return Err(Error::InvalidArgument("Dirty pages are logged to the \
+                                               dirty rings"));
+        }
+        let page_size = page_size();
+        let len = mem_slot.memory.len() as u64;
+        let num_pages = (len + page_size - 1) / page_size;
+        // The kernel fills in whole longs
+        let mut bits = vec![0u64; ((num_pages + 63) / 64) as usize];
+        let log = DirtyLog {
+            slot,
+            dirty_bitmap: bits.as_mut_ptr() as u64,
+            ..Default::default()
+        };
+        let ret = unsafe {
+            ioctl_with_ref(self.fd.as_raw_fd(), KVM_GET_DIRTY_LOG, &log)
+        };
+        if ret != 0 {
+            return Err(ioctl_error(Operation::GetDirtyLog));
+        }
+        Ok(DirtyBitmap {
+            slot,
+            guest_phys_addr: mem_slot.guest_phys_addr,
+            page_size,
+            num_pages,
+            bits,
+        })
+    }
+
+    /// Enable manual protection of dirty pages.
+    ///
+    /// Afterwards `get_dirty_log` no longer write protects the pages it
+    /// returns, so the whole bitmap need not be reset on every call. Instead
+    /// `clear_dirty_log` must be used to protect pages again, in as small
+    /// chunks as desired, before their contents are copied. If
+    /// `initially_set`, every page of a new memory slot starts out dirty so
+    /// that write protecting large slots up front is avoided.
+    pub fn enable_manual_dirty_log_protect(&self,
+                                           initially_set: bool)
+                                           -> Result<()> {
+        let supported =
+            self.check_capability(Capability::ManualDirtyLogProtect2) as u64;
+        let mut flags = DIRTY_LOG_MANUAL_PROTECT_ENABLE;
+        if initially_set {
+            flags |= DIRTY_LOG_INITIALLY_SET;
+        }
+        if supported & flags != flags {
+            return Err(Error::InvalidArgument("Manual dirty log protection \
+                                               is not supported"));
+        }
+        self.enable_cap(Capability::ManualDirtyLogProtect2, [flags, 0, 0, 0])
+    }
+
+    /// Write protect pages which were reported by `get_dirty_log`.
+    ///
+    /// Only the pages set in `bitmap` within `num_pages` pages from
+    /// `first_page` are cleared and protected. `first_page` must be a
+    /// multiple of 64, as must `num_pages` unless the range extends to the
+    /// end of the memory slot, and the range must lie within both the bitmap
+    /// and the memory slot as it is now. This requires that manual
+    /// protection has been enabled with `enable_manual_dirty_log_protect`.
+    pub fn clear_dirty_log(&self,
+                           bitmap: &DirtyBitmap,
+                           first_page: u64,
+                           num_pages: u64)
+                           -> Result<()> {
+        let mem_slots = self.mem_slots.lock().unwrap();
+        let slot_pages = match mem_slots.get(&bitmap.slot) {
+            Some(mem_slot) => {
+                let len = mem_slot.memory.len() as u64;
+                (len + bitmap.page_size - 1) / bitmap.page_size
+            }
+            None => return Err(Error::InvalidMemorySlot(bitmap.slot)),
+        };
+        let end = first_page.checked_add(num_pages).unwrap_or(!0);
+        if first_page % 64 != 0 || end > bitmap.num_pages ||
+           end > slot_pages ||
+           (num_pages % 64 != 0 && end != slot_pages) {
+            return Err(Error::InvalidArgument("Dirty page range is out of \
+                                               bounds or not aligned"));
+        }
+        if num_pages == 0 {
+            return Ok(());
+        }
+        let bits = &bitmap.bits[(first_page / 64) as usize..];
+        let log = ClearDirtyLog {
+            slot: bitmap.slot,
+            num_pages: num_pages as u32,
+            first_page,
+            dirty_bitmap: bits.as_ptr() as u64,
+        };
+        let ret = unsafe {
+            ioctl_with_ref(self.fd.as_raw_fd(), KVM_CLEAR_DIRTY_LOG, &log)
+        };
+        if ret != 0 {
+            return Err(ioctl_error(Operation::ClearDirtyLog));
+        }
+        Ok(())
+    }
+
+    /// Log dirty pages to a ring per `Vcpu` rather than a bitmap per slot.
+    ///
+    /// Each ring holds `entries` entries, which must be a power of two.
+    /// Pages are still only logged for memory slots established with
+    /// `MEM_LOG_DIRTY_PAGES`, but are retrieved with
+    /// `Vcpu::dirty_ring_entries` instead of `get_dirty_log`. This must be
+    /// done before any `Vcpu` is created.
+    pub fn enable_dirty_ring(&self, entries: u32) -> Result<()> {
+        let vcpu_ids = self.vcpu_ids.lock().unwrap();
+        if !vcpu_ids.is_empty() {
+            return Err(Error::InvalidArgument("Dirty rings must be enabled \
+                                               before creating vcpus"));
+        }
+        let mut cap = Capability::DirtyLogRing;
+        let mut max_size = self.check_capability(cap);
+        if max_size <= 0 {
+            cap = Capability::DirtyLogRingAcqRel;
+            max_size = self.check_capability(cap);
+        }
+        let size = entries as u64 * size_of::<DirtyGfn>() as u64;
+        if max_size <= 0 {
+            return Err(Error::InvalidArgument("Dirty rings are not \
+                                               supported"));
+        } else if !entries.is_power_of_two() || size > max_size as u64 {
+            return Err(Error::InvalidArgument("Invalid dirty ring size"));
+        }
+        self.enable_cap(cap, [size, 0, 0, 0])?;
+        self.dirty_ring_entries.store(entries as usize, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Allow the kernel to reuse dirty ring entries which have been
+    /// harvested.
+    ///
+    /// This applies to the dirty rings of all `Vcpu`s and write protects the
+    /// harvested pages again, so it must be called before their contents are
+    /// copied. Returns the number of entries reset.
+    pub fn reset_dirty_rings(&self) -> Result<u32> {
+        let ret = unsafe { ioctl(self.fd.as_raw_fd(), KVM_RESET_DIRTY_RINGS) };
+        if ret < 0 {
+            return Err(ioctl_error(Operation::ResetDirtyRings));
+        }
+        Ok(ret as u32)
+    }
+
+    fn enable_cap(&self, cap: Capability, args: [u64; 4]) -> Result<()> {
+        let enable_cap = EnableCap {
+            cap: cap as u32,
+            flags: 0,
+            args,
+            pad: [0; 64],
+        };
+        let ret = unsafe {
+            ioctl_with_ref(self.fd.as_raw_fd(), KVM_ENABLE_CAP, &enable_cap)
+        };
+        if ret != 0 {
+            return Err(ioctl_error(Operation::EnableCap));
+        }
+        Ok(())
+    }
+
+    fn set_memory_region_raw(&self, region: &UserspaceMemoryRegion)
+                             -> Result<()> {
+        let ret = unsafe {
+            ioctl_with_ref(self.fd.as_raw_fd(),
+                           KVM_SET_USER_MEMORY_REGION,
+                           region)
+        };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(Error::SetUserMemoryRegion {
+                slot: region.slot,
+                guest_phys_addr: region.guest_phys_addr,
+                errno: errno().0,
+            })
+        }
+    }
+
+    /// Create an in-kernel interrupt controller.
+    ///
+    /// On x86 this creates the PICs, the IOAPIC and a local APIC for every
+    /// `Vcpu`, so it must be called before any `Vcpu` is created. This
+    /// requires `Capability::Irqchip`.
+    pub fn create_irqchip(&self) -> Result<()> {
+        let ret = unsafe { ioctl(self.fd.as_raw_fd(), KVM_CREATE_IRQCHIP) };
+        if ret != 0 {
+            return Err(ioctl_error(Operation::CreateIrqchip));
+        }
+        Ok(())
+    }
+
+    /// Set the level of an interrupt line of the in-kernel irqchip
+    ///
+    /// Edge triggered interrupts require the line to be raised and lowered.
+    pub fn set_irq_line(&self, gsi: u32, level: bool) -> Result<()> {
+        let irq_level = IrqLevel {
+            irq: gsi,
+            level: level as u32,
+        };
+        let ret = unsafe {
+            ioctl_with_ref(self.fd.as_raw_fd(), KVM_IRQ_LINE, &irq_level)
+        };
+        if ret != 0 {
+            return Err(ioctl_error(Operation::IrqLine));
+        }
+        Ok(())
+    }
+
+    /// Set the level of an interrupt line and report whether the interrupt
+    /// was delivered.
+    ///
+    /// This requires `Capability::IrqInjectStatus`.
+    pub fn set_irq_line_status(&self,
+                               gsi: u32,
+                               level: bool)
+                               -> Result<IrqStatus> {
+        let mut irq_level = IrqLevel {
+            irq: gsi,
+            level: level as u32,
+        };
+        let ret = unsafe {
+            ioctl_with_mut_ref(self.fd.as_raw_fd(),
+                               KVM_IRQ_LINE_STATUS,
+                               &mut irq_level)
+        };
+        if ret != 0 {
+            return Err(ioctl_error(Operation::IrqLine));
+        }
+        let status = irq_level.irq as i32;
+        Ok(if status > 0 {
+            IrqStatus::Delivered(status as u32)
+        } else if status == 0 {
+            IrqStatus::Coalesced
+        } else {
+            IrqStatus::Masked
+        })
+    }
+
+    /// Inject an MSI without routing it through a GSI.
+    ///
+    /// The device id is required by some interrupt controllers, see
+    /// `Capability::MsiDevid`. This requires an in-kernel irqchip and
+    /// `Capability::SignalMsi`. The result is either `IrqStatus::Delivered`
+    /// or `IrqStatus::Blocked`.
+    pub fn signal_msi(&self,
+                      address: u64,
+                      data: u32,
+                      devid: Option<u32>)
+                      -> Result<IrqStatus> {
+        let mut msi = Msi {
+            address_lo: address as u32,
+            address_hi: (address >> 32) as u32,
+            data,
+            ..Default::default()
+        };
+        if let Some(devid) = devid {
+            msi.flags = MSI_VALID_DEVID;
+            msi.devid = devid;
+        }
+        let ret = unsafe {
+            ioctl_with_ref(self.fd.as_raw_fd(), KVM_SIGNAL_MSI, &msi)
+        };
+        if ret < 0 {
+            return Err(ioctl_error(Operation::SignalMsi));
+        }
+        Ok(if ret > 0 {
+            IrqStatus::Delivered(ret as u32)
+        } else {
+            IrqStatus::Blocked
+        })
+    }
+
+    /// Raise an interrupt on `gsi` whenever `fd` is signalled.
+    ///
+    /// The interrupt is edge triggered. This requires an in-kernel irqchip
+    /// and `Capability::Irqfd`.
+    pub fn register_irqfd(&self, fd: &EventFd, gsi: u32) -> Result<()> {
+        self.irqfd(Irqfd {
+            fd: fd.as_raw_fd() as u32,
+            gsi,
+            ..Default::default()
+        })
+    }
+
+    /// Raise a level triggered interrupt on `gsi` whenever `fd` is
+    /// signalled.
+    ///
+    /// The interrupt is lowered when the guest acknowledges it, at which
+    /// point `resample` is signalled so that the device can check whether
+    /// to raise it again. This requires `Capability::IrqfdResample`.
+    pub fn register_irqfd_with_resample(&self,
+                                        fd: &EventFd,
+                                        resample: &EventFd,
+                                        gsi: u32)
+                                        -> Result<()> {
+        self.irqfd(Irqfd {
+            fd: fd.as_raw_fd() as u32,
+            gsi,
+            flags: IRQFD_FLAG_RESAMPLE,
+            resamplefd: resample.as_raw_fd() as u32,
+            ..Default::default()
+        })
+    }
+
+    /// Stop raising an interrupt on `gsi` when `fd` is signalled
+    pub fn unregister_irqfd(&self, fd: &EventFd, gsi: u32) -> Result<()> {
+        self.irqfd(Irqfd {
+            fd: fd.as_raw_fd() as u32,
+            gsi,
+            flags: IRQFD_FLAG_DEASSIGN,
+            ..Default::default()
+        })
+    }
+
+    /// Signal `fd` instead of exiting when the guest writes to `addr`.
+    ///
+    /// Only writes of `len` bytes match, which must be 1, 2, 4 or 8, or 0
+    /// to match writes of any length to MMIO addresses if
+    /// `Capability::IoeventfdAnyLength`. If `datamatch` is given, only writes
+    /// of that value match. This requires `Capability::Ioeventfd`.
+    pub fn register_ioevent(&self,
+                            addr: IoEventAddress,
+                            len: u32,
+                            datamatch: Option<u64>,
+                            fd: &EventFd)
+                            -> Result<()> {
+        self.ioeventfd(addr, len, datamatch, fd, 0)
+    }
+
+    /// Stop signalling `fd` when the guest writes to `addr`
+    ///
+    /// The arguments must be the same as those given to `register_ioevent`.
+    pub fn unregister_ioevent(&self,
+                              addr: IoEventAddress,
+                              len: u32,
+                              datamatch: Option<u64>,
+                              fd: &EventFd)
+                              -> Result<()> {
+        self.ioeventfd(addr, len, datamatch, fd, IOEVENTFD_FLAG_DEASSIGN)
+    }
+
+    fn ioeventfd(&self,
+                 addr: IoEventAddress,
+                 len: u32,
+                 datamatch: Option<u64>,
+                 fd: &EventFd,
+                 mut flags: u32)
+                 -> Result<()> {
+        let addr = match addr {
+            IoEventAddress::Pio(port) => {
+                flags |= IOEVENTFD_FLAG_PIO;
+                port as u64
+            }
+            IoEventAddress::Mmio(addr) => addr,
+        };
+        if datamatch.is_some() {
+            flags |= IOEVENTFD_FLAG_DATAMATCH;
+        }
+        let ioeventfd = Ioeventfd {
+            datamatch: datamatch.unwrap_or(0),
+            addr,
+            len,
+            fd: fd.as_raw_fd(),
+            flags,
+            pad: [0; 36],
+        };
+        let ret = unsafe {
+            ioctl_with_ref(self.fd.as_raw_fd(), KVM_IOEVENTFD, &ioeventfd)
+        };
+        if ret != 0 {
+            return Err(ioctl_error(Operation::Ioeventfd));
+        }
+        Ok(())
+    }
+
+    fn irqfd(&self, irqfd: Irqfd) -> Result<()> {
+        let ret = unsafe {
+            ioctl_with_ref(self.fd.as_raw_fd(), KVM_IRQFD, &irqfd)
+        };
+        if ret != 0 {
+            return Err(ioctl_error(Operation::Irqfd));
+        }
+        Ok(())
+    }
+
+    /// Replace the GSI routing table.
+    ///
+    /// The whole table is replaced at once. The number of routes and the
+    /// GSIs must both be below the limit given by `Capability::IrqRouting`.
+    pub fn set_gsi_routing(&self, table: &GsiRoutingTable) -> Result<()> {
+        let max_routes = self.check_capability(Capability::IrqRouting);
+        if max_routes <= 0 {
+            return Err(Error::InvalidArgument("GSI routing is not \
+                                               supported"));
+        }
+        let max_routes = max_routes as u32;
+        if table.entries.len() > max_routes as usize ||
+           table.entries.iter().any(|e| e.gsi >= max_routes) {
+            return Err(Error::InvalidArgument("GSI routing table exceeds the \
+                                               limit of KVM_CAP_IRQ_ROUTING"));
+        }
+        // The entries follow the header, so build them up in a buffer of u64
+        // to get the alignment right
+        let len = size_of::<IrqRouting>() +
+                  table.entries.len() * size_of::<IrqRoutingEntry>();
+        let mut buf = vec![0u64; (len + 7) / 8];
+        let routing = buf.as_mut_ptr() as *mut IrqRouting;
+        let ret = unsafe {
+            (*routing).nr = table.entries.len() as u32;
+            ptr::copy_nonoverlapping(table.entries.as_ptr(),
+                                     routing.offset(1) as *mut IrqRoutingEntry,
+                                     table.entries.len());
+            ioctl_with_ref(self.fd.as_raw_fd(), KVM_SET_GSI_ROUTING, &*routing)
+        };
+        if ret != 0 {
+            return Err(ioctl_error(Operation::SetGsiRouting));
+        }
+        Ok(())
+    }
+
+    /// Create a `Vcpu` with the specified id
+    ///
+    /// Ids need not be contiguous, but must be below
+    /// `System::max_vcpu_id`. On x86 the id is used as the initial APIC ID.
+    pub fn create_vcpu(self: &Arc<Self>, id: u32) -> Result<Vcpu> {
+        let mut vcpu_ids = self.vcpu_ids.lock().unwrap();
+        Vcpu::create_locked(self, &mut vcpu_ids, id)
+    }
+}
+
+/// Do not emulate the PC speaker port alongside the PIT
+#[cfg(target_arch = "x86_64")]
+pub const PIT_SPEAKER_DUMMY: u32 = 1;
+
+/// Use 32-bit APIC IDs in `LapicState` for APICs in x2APIC mode
+#[cfg(target_arch = "x86_64")]
+pub const X2APIC_API_USE_32BIT_IDS: u64 = 1;
+/// Do not treat an x2APIC destination of 0xff as a broadcast
+#[cfg(target_arch = "x86_64")]
+pub const X2APIC_API_DISABLE_BROADCAST_QUIRK: u64 = 1 << 1;
+
+#[cfg(target_arch = "x86_64")]
+impl VirtualMachine {
+    /// Change how x2APIC IDs are handled.
+    ///
+    /// Flags is the bitwise or of `X2APIC_API_USE_32BIT_IDS` and/or
+    /// `X2APIC_API_DISABLE_BROADCAST_QUIRK`. This requires
+    /// `Capability::X2apicApi`.
+    pub fn enable_x2apic_api(&self, flags: u64) -> Result<()> {
+        self.enable_cap(Capability::X2apicApi, [flags, 0, 0, 0])?;
+        self.x2apic_ids.store(flags & X2APIC_API_USE_32BIT_IDS != 0,
+                              Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Report exceptions as pending, with their payload, in `VcpuEvents`.
+    ///
+    /// Without this KVM delivers the payload of an exception, such as the
+    /// faulting address of a page fault, as soon as it is queued, which
+    /// loses it for a nested hypervisor if the state is saved in between.
+    /// This requires `Capability::ExceptionPayload`.
+    pub fn enable_exception_payload(&self) -> Result<()> {
+        self.enable_cap(Capability::ExceptionPayload, [1, 0, 0, 0])
+    }
+
+    /// Create only the local APICs in the kernel, leaving the PICs and the
+    /// IOAPIC to userspace.
+    ///
+    /// The first `ioapic_pins` GSIs are reserved for a userspace IOAPIC such
+    /// as `Ioapic`. This must be called before any `Vcpu` is created, and
+    /// instead of `create_irqchip`. This requires `Capability::SplitIrqchip`.
+    pub fn enable_split_irqchip(&self, ioapic_pins: u32) -> Result<()> {
+        self.enable_cap(Capability::SplitIrqchip,
+                        [ioapic_pins as u64, 0, 0, 0])
+    }
+
+    /// Create an in-kernel PIT.
+    ///
+    /// Flags is 0 or `PIT_SPEAKER_DUMMY`. This must be called after
+    /// `create_irqchip` and requires `Capability::Pit2`.
+    pub fn create_pit2(&self, flags: u32) -> Result<()> {
+        let config = PitConfig {
+            flags,
+            ..Default::default()
+        };
+        let ret = unsafe {
+            ioctl_with_ref(self.fd.as_raw_fd(), KVM_CREATE_PIT2, &config)
+        };
+        if ret != 0 {
+            return Err(ioctl_error(Operation::CreatePit2));
+        }
+        Ok(())
+    }
+
+    /// Get the state of the in-kernel PIT
+    ///
+    /// This requires `Capability::PitState2`.
+    pub fn get_pit2(&self) -> Result<PitState2> {
+        let mut pit = PitState2::default();
+        let ret = unsafe {
+            ioctl_with_mut_ref(self.fd.as_raw_fd(), KVM_GET_PIT2, &mut pit)
+        };
+        if ret != 0 {
+            return Err(ioctl_error(Operation::GetPit2));
+        }
+        Ok(pit)
+    }
+
+    /// Set the state of the in-kernel PIT
+    pub fn set_pit2(&self, pit: &PitState2) -> Result<()> {
+        let ret = unsafe {
+            ioctl_with_ref(self.fd.as_raw_fd(), KVM_SET_PIT2, pit)
+        };
+        if ret != 0 {
+            return Err(ioctl_error(Operation::SetPit2));
+        }
+        Ok(())
+    }
+
+    /// Choose whether PIT interrupts missed by the guest are reinjected.
+    ///
+    /// Reinjection is the default, which keeps guests that count ticks in
+    /// time. Guests which read the time from elsewhere run more smoothly
+    /// without it. This requires `Capability::ReinjectControl`.
+    pub fn set_pit_reinject(&self, reinject: bool) -> Result<()> {
+        let control = ReinjectControl {
+            pit_reinject: reinject as u8,
+            ..Default::default()
+        };
+        let ret = unsafe {
+            ioctl_with_ref(self.fd.as_raw_fd(), KVM_REINJECT_CONTROL, &control)
+        };
+        if ret != 0 {
+            return Err(ioctl_error(Operation::ReinjectControl));
+        }
+        Ok(())
+    }
+
+    /// Get the state of an in-kernel interrupt controller
+    pub fn get_irqchip(&self, id: IrqchipId) -> Result<IrqchipState> {
+        let mut chip = Irqchip {
+            chip_id: id as u32,
+            pad: 0,
+            chip: [0; 64],
+        };
+        let ret = unsafe {
+            ioctl_with_mut_ref(self.fd.as_raw_fd(), KVM_GET_IRQCHIP, &mut chip)
+        };
+        if ret != 0 {
+            return Err(ioctl_error(Operation::GetIrqchip));
+        }
+        let ptr = chip.chip.as_ptr();
+        Ok(unsafe {
+            match id {
+                IrqchipId::PicMaster => {
+                    IrqchipState::PicMaster(ptr::read(ptr as *const PicState))
+                }
+                IrqchipId::PicSlave => {
+                    IrqchipState::PicSlave(ptr::read(ptr as *const PicState))
+                }
+                IrqchipId::Ioapic => {
+                    IrqchipState::Ioapic(ptr::read(ptr as *const IoapicState))
+                }
+            }
+        })
+    }
+
+    /// Set the state of an in-kernel interrupt controller
+    pub fn set_irqchip(&self, state: &IrqchipState) -> Result<()> {
+        let mut chip = Irqchip {
+            chip_id: state.id() as u32,
+            pad: 0,
+            chip: [0; 64],
+        };
+        let ptr = chip.chip.as_mut_ptr();
+        unsafe {
+            match *state {
+                IrqchipState::PicMaster(pic) |
+                IrqchipState::PicSlave(pic) => {
+                    ptr::write(ptr as *mut PicState, pic)
+                }
+                IrqchipState::Ioapic(ioapic) => {
+                    ptr::write(ptr as *mut IoapicState, ioapic)
+                }
+            }
+        }
+        let ret = unsafe {
+            ioctl_with_ref(self.fd.as_raw_fd(), KVM_SET_IRQCHIP, &chip)
+        };
+        if ret != 0 {
+            return Err(ioctl_error(Operation::SetIrqchip));
+        }
+        Ok(())
+    }
+}
+
+impl Vcpu {
+    /// Create a `Vcpu` on the specified `VirtualMachine`
+    ///
+    /// The `Vcpu` is given the lowest id not already in use.
+    pub fn create(vm: &Arc<VirtualMachine>) -> Result<Self> {
+        let mut vcpu_ids = vm.vcpu_ids.lock().unwrap();
+        let id = (0..).find(|id| !vcpu_ids.contains(id)).unwrap();
+        Vcpu::create_locked(vm, &mut vcpu_ids, id)
+    }
+
+    fn create_locked(vm: &Arc<VirtualMachine>,
+                     vcpu_ids: &mut BTreeSet<u32>,
+                     id: u32)
+                     -> Result<Self> {
+        let num_vcpus = vcpu_ids.len() as u32;
+        let max_vcpus = vm.sys.max_vcpus();
+        if num_vcpus >= max_vcpus {
+            return Err(Error::TooManyVcpus { max: max_vcpus });
+        } else if num_vcpus >= vm.sys.recommended_vcpus() {
+            warn!("Exceeding recommended_vcpus");
+        }
+        if id >= vm.sys.max_vcpu_id() {
+            return Err(Error::InvalidArgument("Vcpu id exceeds max_vcpu_id"));
+        }
+        let mmap_size = vm.sys.get_vcpu_mmap_size()?;
+        let ret = unsafe {
+            ioctl_with_val(vm.fd.as_raw_fd(), KVM_CREATE_VCPU, id as c_ulong)
+        };
+        let fd = match unsafe { file_from_ioctl(ret) } {
+            Some(fd) => fd,
+            None => {
+                return Err(Error::CreateVcpu {
+                    id,
+                    errno: errno().0,
+                })
+            }
+        };
+        // The kernel has now claimed the id, even if mapping fails below
+        vcpu_ids.insert(id);
+        let m = Mmap::open_with_offset(&fd,
+                                       Protection::ReadWrite,
+                                       0,
+                                       mmap_size)
+                    .map_err(|e| {
+                        Error::MapVcpu {
+                            id,
+                            error: e,
+                        }
+                    })?;
+        let entries = vm.dirty_ring_entries.load(Ordering::Relaxed);
+        let dirty_ring = if entries != 0 {
+            let offset = DIRTY_LOG_PAGE_OFFSET * page_size() as usize;
+            let len = entries * size_of::<DirtyGfn>();
+            Some(Mmap::open_with_offset(&fd,
+                                        Protection::ReadWrite,
+                                        offset,
+                                        len)
+                     .map_err(|e| {
+                         Error::MapVcpu {
+                             id,
+                             error: e,
+                         }
+                     })?)
+        } else {
+            None
+        };
+        Ok(Vcpu {
+            id,
+            fd,
+            vm: vm.clone(),
+            mmap: m,
+            dirty_ring,
+            dirty_ring_index: 0,
+        })
+    }
+
+    /// Run the `Vcpu` until it exits back to userspace
+    pub fn run(&mut self) -> Result<VcpuExit<'_>> {
+        let ret = unsafe { ioctl(self.fd.as_raw_fd(), KVM_RUN) };
+        if ret != 0 {
+            return Err(self.ioctl_error(Operation::Run));
+        }
+        let io_range = self.io_data_range();
+        let base = self.mmap.mut_ptr();
+        // The returned exit borrows self mutably, so nothing else can touch
+        // the run page until it is dropped.
+        let exit = unsafe {
+            let run = &mut *(base as *mut Run);
+            match run.reason() {
+                Exit::Unknown => {
+                    let hw = &*run.hw();
+                    VcpuExit::Unknown {
+                        hardware_exit_reason: hw.hardware_exit_reason,
+                    }
+                }
+                Exit::Exception => {
+                    let ex = &*run.ex();
+                    VcpuExit::Exception {
+                        exception: ex.exception,
+                        error_code: ex.error_code,
+                    }
+                }
+                Exit::Io => {
+                    let io = &*run.io();
+                    let (offset, len) = io_range.unwrap();
+                    let data = base.add(offset);
+                    match io.direction {
+                        IO_IN => VcpuExit::IoIn {
+                            port: io.port,
+                            size: io.size,
+                            data: slice::from_raw_parts_mut(data, len),
+                        },
+                        IO_OUT => VcpuExit::IoOut {
+                            port: io.port,
+                            size: io.size,
+                            data: slice::from_raw_parts(data, len),
+                        },
+                        _ => VcpuExit::Unsupported(Exit::Io),
+                    }
+                }
+                Exit::Hypercall => {
+                    let hc = &mut *run.hypercall_mut();
+                    VcpuExit::Hypercall {
+                        nr: hc.nr,
+                        args: hc.args,
+                        ret: &mut hc.ret,
+                        longmode: hc.longmode != 0,
+                    }
+                }
+                Exit::Debug => VcpuExit::Debug((*run.debug()).arch),
+                Exit::Hlt => VcpuExit::Hlt,
+                Exit::Mmio => {
+                    let mmio = &mut *run.mmio_mut();
+                    let len = cmp::min(mmio.len as usize, mmio.data.len());
+                    if mmio.is_write != 0 {
+                        VcpuExit::MmioWrite {
+                            addr: mmio.phys_addr,
+                            data: &mmio.data[..len],
+                        }
+                    } else {
+                        VcpuExit::MmioRead {
+                            addr: mmio.phys_addr,
+                            data: &mut mmio.data[..len],
+                        }
+                    }
+                }
+                Exit::IrqWindowOpen => VcpuExit::IrqWindowOpen,
+                Exit::Shutdown => VcpuExit::Shutdown,
+                Exit::FailEntry => {
+                    let fe = &*run.fail_entry();
+                    VcpuExit::FailEntry {
+                        hardware_entry_failure_reason:
+                            fe.hardware_entry_failure_reason,
+                    }
+                }
+                Exit::Intr => VcpuExit::Intr,
+                Exit::SetTpr => VcpuExit::SetTpr,
+                Exit::TprAccess => {
+                    let tpr = &*run.tpr_access();
+                    VcpuExit::TprAccess {
+                        rip: tpr.rip,
+                        is_write: tpr.is_write != 0,
+                    }
+                }
+                Exit::S390Sieic => {
+                    let sieic = &*run.s390_sieic();
+                    VcpuExit::S390Sieic {
+                        icptcode: sieic.icptcode,
+                        ipa: sieic.ipa,
+                        ipb: sieic.ipb,
+                    }
+                }
+                Exit::S390Reset => {
+                    VcpuExit::S390Reset { flags: *run.s390_reset_flags() }
+                }
+                Exit::Dcr => {
+                    let dcr = &mut *run.dcr_mut();
+                    VcpuExit::Dcr {
+                        dcrn: dcr.dcrn,
+                        data: &mut dcr.data,
+                        is_write: dcr.is_write != 0,
+                    }
+                }
+                Exit::Nmi => VcpuExit::Nmi,
+                Exit::InternalError => {
+                    let internal = &*run.internal();
+                    let ndata = cmp::min(internal.ndata as usize,
+                                         internal.data.len());
+                    VcpuExit::InternalError {
+                        suberror: internal.suberror,
+                        data: &internal.data[..ndata],
+                    }
+                }
+                Exit::Osi => {
+                    VcpuExit::Osi { gprs: &mut (*run.osi_mut()).gprs }
+                }
+                Exit::PaprHcall => {
+                    let hc = &mut *run.papr_hcall_mut();
+                    VcpuExit::PaprHcall {
+                        nr: hc.nr,
+                        ret: &mut hc.ret,
+                        args: hc.args,
+                    }
+                }
+                Exit::S390Ucontrol => {
+                    let ucontrol = &*run.s390_ucontrol();
+                    VcpuExit::S390Ucontrol {
+                        trans_exc_code: ucontrol.trans_exc_code,
+                        pgm_code: ucontrol.pgm_code,
+                    }
+                }
+                Exit::Watchdog => VcpuExit::Watchdog,
+                Exit::S390Tsch => {
+                    let tsch = &*run.s390_tsch();
+                    VcpuExit::S390Tsch {
+                        subchannel_id: tsch.subchannel_id,
+                        subchannel_nr: tsch.subchannel_nr,
+                        io_int_parm: tsch.io_int_parm,
+                        io_int_word: tsch.io_int_word,
+                        ipb: tsch.ipb,
+                        dequeued: tsch.dequeued != 0,
+                    }
+                }
+                Exit::Epr => VcpuExit::Epr { epr: &mut (*run.epr_mut()).epr },
+                Exit::SystemEvent => {
+                    let event = &*run.system_event();
+                    VcpuExit::SystemEvent {
+                        kind: event._type,
+                        flags: event.flags,
+                    }
+                }
+                Exit::IoapicEoi => {
+                    VcpuExit::IoapicEoi { vector: (*run.eoi()).vector }
+                }
+                Exit::DirtyRingFull => VcpuExit::DirtyRingFull,
+                reason => VcpuExit::Unsupported(reason),
+            }
+        };
+        Ok(exit)
+    }
+
+    /// Harvest the pages logged to the dirty ring of this `Vcpu`.
+    ///
+    /// Yields `(slot, page)` pairs where `page` is the index of the page
+    /// within the memory slot. Harvested entries are only reused by the
+    /// kernel once `VirtualMachine::reset_dirty_rings` is called. If the
+    /// dirty rings are not enabled nothing is yielded.
+    pub fn dirty_ring_entries(&mut self) -> DirtyRingEntries<'_> {
+        let (ring, count) = match self.dirty_ring {
+            Some(ref mut m) => {
+                (m.mut_ptr() as *mut DirtyGfn,
+                 (m.len() / size_of::<DirtyGfn>()) as u32)
+            }
+            None => (ptr::null_mut(), 0),
+        };
+        DirtyRingEntries {
+            ring,
+            count,
+            index: &mut self.dirty_ring_index,
+        }
+    }
+
+    /// The data area of the last `VcpuExit::IoIn` or `VcpuExit::IoOut`
+    ///
+    /// For string I/O this covers all `count` accesses of `size` bytes.
+    /// Returns `None` if the last exit was not for I/O.
+    pub fn io_data(&self) -> Option<&[u8]> {
+        self.io_data_range().map(|(offset, len)| unsafe {
+            slice::from_raw_parts(self.mmap.ptr().add(offset), len)
+        })
+    }
+
+    /// Mutable access to the data area of the last I/O exit
+    ///
+    /// Data written here completes an `in` instruction when the `Vcpu` is
+    /// next run.
+    pub fn io_data_mut(&mut self) -> Option<&mut [u8]> {
+        match self.io_data_range() {
+            Some((offset, len)) => unsafe {
+                let data = self.mmap.mut_ptr().add(offset);
+                Some(slice::from_raw_parts_mut(data, len))
+            },
+            None => None,
+        }
+    }
+
+    /// Provide the result of the last `VcpuExit::MmioRead`
+    ///
+    /// `data` must be exactly as long as the access made by the guest. The
+    /// read completes when the `Vcpu` is next run.
+    pub fn set_mmio_read_data(&mut self, data: &[u8]) -> Result<()> {
+        let run = unsafe { &mut *(self.mmap.mut_ptr() as *mut Run) };
+        if run.reason() != Exit::Mmio {
+            return Err(Error::InvalidArgument("Last exit was not for MMIO"));
+        }
+        let mmio = unsafe { &mut *run.mmio_mut() };
+        if mmio.is_write != 0 {
+            return Err(Error::InvalidArgument("Last MMIO exit was a write"));
+        }
+        if data.len() != mmio.len as usize || data.len() > mmio.data.len() {
+            return Err(Error::InvalidArgument("MMIO data length mismatch"));
+        }
+        mmio.data[..data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Request a `VcpuExit::IrqWindowOpen` once the guest can accept an
+    /// interrupt
+    ///
+    /// The request stays in place until it is cleared, so it should be
+    /// cleared once the pending interrupts have been injected.
+    pub fn set_request_interrupt_window(&mut self, request: bool) {
+        let run = unsafe { &mut *(self.mmap.mut_ptr() as *mut Run) };
+        run.request_interrupt_window = request as u8;
+    }
+
+    /// Whether an interrupt can be injected when the `Vcpu` is next run
+    ///
+    /// This is updated on every exit.
+    pub fn ready_for_interrupt_injection(&self) -> bool {
+        let run = unsafe { &*(self.mmap.ptr() as *const Run) };
+        run.ready_for_interrupt_injection != 0
+    }
+
+    /// Whether the guest had interrupts enabled at the last exit
+    pub fn interrupts_enabled(&self) -> bool {
+        let run = unsafe { &*(self.mmap.ptr() as *const Run) };
+        run.if_flag != 0
+    }
+
+    /// The id of this `Vcpu`
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn ioctl_error(&self, op: Operation) -> Error {
+        Error::Vcpu {
+            id: self.id,
+            op,
+            errno: errno().0,
+        }
+    }
+
+    fn io_data_range(&self) -> Option<(usize, usize)> {
+        let run = unsafe { &*(self.mmap.ptr() as *const Run) };
+        if run.reason() != Exit::Io {
+            return None;
+        }
+        let io = unsafe { &*run.io() };
+        let offset = io.data_offset as usize;
+        let len = io.size as usize * io.count as usize;
+        assert!(offset + len <= self.mmap.len());
+        Some((offset, len))
+    }
+
+    /// Get registers
+    pub fn get_regs(&self) -> Result<Regs> {
+        let mut regs = Regs::default();
+        let ret = unsafe {
+            ioctl_with_mut_ref(self.fd.as_raw_fd(), KVM_GET_REGS, &mut regs)
+        };
+        if ret == 0 {
+            Ok(regs)
+        } else {
+            Err(self.ioctl_error(Operation::GetRegs))
+        }
+    }
+
+    /// Set registers
+    pub fn set_regs(&mut self, regs: &Regs) -> Result<()> {
+        let ret = unsafe {
+            ioctl_with_ref(self.fd.as_raw_fd(), KVM_SET_REGS, regs)
+        };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(self.ioctl_error(Operation::SetRegs))
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Vcpu {
+    /// Set the response to the CPUID instruction
+    pub fn set_cpuid2(&mut self, cpuid: &mut Cpuid2) -> Result<()> {
+        let ret = unsafe {
+            ioctl_with_ref(self.fd.as_raw_fd(), KVM_SET_CPUID2, cpuid)
+        };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(self.ioctl_error(Operation::SetCpuid2))
+        }
+    }
+    /// Get special registers
+    pub fn get_sregs(&self) -> Result<Sregs> {
+        let mut sregs = Sregs::default();
+        let ret = unsafe {
+            ioctl_with_mut_ref(self.fd.as_raw_fd(), KVM_GET_SREGS, &mut sregs)
+        };
+        if ret == 0 {
+            Ok(sregs)
+        } else {
+            Err(self.ioctl_error(Operation::GetSregs))
+        }
+    }
+    /// Set special registers
+    pub fn set_sregs(&mut self, sregs: &Sregs) -> Result<()> {
+        let ret = unsafe {
+            ioctl_with_ref(self.fd.as_raw_fd(), KVM_SET_SREGS, sregs)
+        };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(self.ioctl_error(Operation::SetSregs))
+        }
+    }
+    /// Get the values of MSRs
+    ///
+    /// Only the entries up to the first MSR which could not be read are
+    /// returned, so the number of entries may be less than `indices.len()`.
+    pub fn get_msrs(&self, indices: &[u32]) -> Result<MsrHandle> {
+        let mut msrs = MsrHandle::from_indices(indices);
+        let ret = unsafe {
+            ioctl_with_mut_ref(self.fd.as_raw_fd(),
+                               KVM_GET_MSRS,
+                               msrs.deref_mut())
+        };
+        if ret < 0 {
+            return Err(self.ioctl_error(Operation::GetMsrs));
+        }
+        msrs.nmsrs = ret as u32;
+        Ok(msrs)
+    }
+    /// Set the values of MSRs
+    ///
+    /// MSRs are set in order until one fails. Returns the number which were
+    /// set.
+    pub fn set_msrs(&mut self, entries: &[(u32, u64)]) -> Result<usize> {
+        let msrs = MsrHandle::from_entries(entries);
+        let ret = unsafe {
+            ioctl_with_ref(self.fd.as_raw_fd(), KVM_SET_MSRS, msrs.deref())
+        };
+        if ret < 0 {
+            return Err(self.ioctl_error(Operation::SetMsrs));
+        }
+        Ok(ret as usize)
+    }
+    /// Get the local APIC state
+    ///
+    /// This requires an in-kernel local APIC.
+    pub fn get_lapic(&self) -> Result<LapicState> {
+        let mut lapic = LapicState::default();
+        let ret = unsafe {
+            ioctl_with_mut_ref(self.fd.as_raw_fd(), KVM_GET_LAPIC, &mut lapic)
+        };
+        if ret == 0 {
+            Ok(lapic)
+        } else {
+            Err(self.ioctl_error(Operation::GetLapic))
+        }
+    }
+    /// Set the local APIC state
+    pub fn set_lapic(&mut self, lapic: &LapicState) -> Result<()> {
+        let ret = unsafe {
+            ioctl_with_ref(self.fd.as_raw_fd(), KVM_SET_LAPIC, lapic)
+        };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(self.ioctl_error(Operation::SetLapic))
+        }
+    }
+    /// Format of the APIC ID in the `LapicState` of this `Vcpu`
+    ///
+    /// The full x2APIC ID is only used once the APIC is in x2APIC mode and
+    /// `X2APIC_API_USE_32BIT_IDS` has been enabled on the `VirtualMachine`.
+    pub fn apic_id_format(&self) -> Result<ApicIdFormat> {
+        if !self.vm.x2apic_ids.load(Ordering::Relaxed) {
+            return Ok(ApicIdFormat::XApic);
+        }
+        let msrs = self.get_msrs(&[MSR_IA32_APICBASE])?;
+        match msrs.get(MSR_IA32_APICBASE) {
+            Some(base) if base & APICBASE_X2APIC_ENABLE != 0 => {
+                Ok(ApicIdFormat::X2Apic)
+            }
+            _ => Ok(ApicIdFormat::XApic),
+        }
+    }
+    /// Queue an external interrupt for the guest
+    ///
+    /// This is only possible without an in-kernel irqchip, and only once
+    /// `ready_for_interrupt_injection` is set. The interrupt is delivered
+    /// when the `Vcpu` is next run.
+    pub fn inject_interrupt(&mut self, vector: u8) -> Result<()> {
+        let interrupt = Interrupt { irq: vector as u32 };
+        let ret = unsafe {
+            ioctl_with_ref(self.fd.as_raw_fd(), KVM_INTERRUPT, &interrupt)
+        };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(self.ioctl_error(Operation::Interrupt))
+        }
+    }
+    /// Queue a non-maskable interrupt for the guest
+    ///
+    /// This requires `Capability::UserNmi`.
+    pub fn inject_nmi(&mut self) -> Result<()> {
+        if self.vm.check_capability(Capability::UserNmi) == 0 {
+            return Err(Error::InvalidArgument("NMI injection is not \
+                                               supported"));
+        }
+        let ret = unsafe { ioctl(self.fd.as_raw_fd(), KVM_NMI) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(self.ioctl_error(Operation::Nmi))
+        }
+    }
+    /// Queue a system management interrupt for the guest
+    ///
+    /// The guest enters SMM when the `Vcpu` is next run. This requires
+    /// `Capability::X86Smm`.
+    pub fn inject_smi(&mut self) -> Result<()> {
+        if self.vm.check_capability(Capability::X86Smm) == 0 {
+            return Err(Error::InvalidArgument("SMI injection is not \
+                                               supported"));
+        }
+        let ret = unsafe { ioctl(self.fd.as_raw_fd(), KVM_SMI) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(self.ioctl_error(Operation::Smi))
+        }
+    }
+    /// Get pending and in-progress events
+    pub fn get_vcpu_events(&self) -> Result<VcpuEvents> {
+        let mut events = VcpuEvents::default();
+        let ret = unsafe {
+            ioctl_with_mut_ref(self.fd.as_raw_fd(),
+                               KVM_GET_VCPU_EVENTS,
+                               &mut events)
+        };
+        if ret == 0 {
+            Ok(events)
+        } else {
+            Err(self.ioctl_error(Operation::GetVcpuEvents))
+        }
+    }
+    /// Set pending and in-progress events
+    ///
+    /// Optional fields are only set if valid in `events.flags`. Exception
+    /// payloads require `VirtualMachine::enable_exception_payload`.
+    pub fn set_vcpu_events(&mut self, events: &VcpuEvents) -> Result<()> {
+        let ret = unsafe {
+            ioctl_with_ref(self.fd.as_raw_fd(), KVM_SET_VCPU_EVENTS, events)
+        };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(self.ioctl_error(Operation::SetVcpuEvents))
+        }
+    }
+    /// Get floating point state
+    pub fn get_fpu(&self) -> Result<Fpu> {
+        let mut fpu = Fpu::default();
+        let ret = unsafe {
+            ioctl_with_mut_ref(self.fd.as_raw_fd(), KVM_GET_FPU, &mut fpu)
+        };
+        if ret == 0 {
+            Ok(fpu)
+        } else {
+            Err(self.ioctl_error(Operation::GetFpu))
+        }
+    }
+    /// Set floating point state
+    pub fn set_fpu(&mut self, fpu: &Fpu) -> Result<()> {
+        let ret = unsafe {
+            ioctl_with_ref(self.fd.as_raw_fd(), KVM_SET_FPU, fpu)
+        };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(self.ioctl_error(Operation::SetFpu))
+        }
+    }
+}
+
+#[test]
+fn init_test() {
+    System::initialize().unwrap();
+}
+
+#[test]
+fn check_capability_test() {
+    let h = System::initialize().unwrap();
+    assert!(h.check_capability(Capability::UserMemory) != 0);
+}
+
+#[test]
+fn capability_discriminant_test() {
+    assert!(Capability::DirtyLogRingAcqRel as i32 == 223);
+    assert!(Capability::ArmSupportedRegMaskRanges as i32 == 230);
+    assert!(Capability::UserMemory2 as i32 == 231);
+    assert!(Capability::X86GuestMode as i32 == 238);
+}
+
+#[test]
+fn vcpus_test() {
     let h = System::initialize().unwrap();
-    let mut vm = VirtualMachine::create(&h).unwrap();
-    vm.set_user_memory_region(0, slice, 0).unwrap();
+    assert!(h.max_vcpus() >= h.recommended_vcpus());
+}
+
+#[test]
+fn create_vm_test() {
+    let h = System::initialize().unwrap();
+    VirtualMachine::create(&h).unwrap();
+}
+
+#[test]
+fn set_memory_test() {
+    let anon_mmap = Mmap::anonymous(16 * (1 << 12), Protection::ReadWrite)
+                        .unwrap();
+    let h = System::initialize().unwrap();
+    let vm = VirtualMachine::create(&h).unwrap();
+    vm.set_user_memory_region(0, anon_mmap, 0).unwrap();
 }
 
 #[test]
 fn create_vcpu_test() {
     let h = System::initialize().unwrap();
-    let mut vm = VirtualMachine::create(&h).unwrap();
-    Vcpu::create(&mut vm).unwrap();
+    let vm = VirtualMachine::create(&h).unwrap();
+    Vcpu::create(&vm).unwrap();
 }
 
 #[cfg(target_arch = "x86_64")]
@@ -1089,8 +3338,8 @@ fn cpuid_test() {
     let h = System::initialize().unwrap();
     if h.check_capability(Capability::ExtCpuid) == 1 {
         let mut cpuid = h.get_supported_cpuid().unwrap();
-        let mut vm = VirtualMachine::create(&h).unwrap();
-        let mut vcpu = Vcpu::create(&mut vm).unwrap();
+        let vm = VirtualMachine::create(&h).unwrap();
+        let mut vcpu = Vcpu::create(&vm).unwrap();
         vcpu.set_cpuid2(&mut cpuid).unwrap();
     }
 }
@@ -1099,8 +3348,8 @@ fn cpuid_test() {
 #[test]
 fn sreg_test() {
     let h = System::initialize().unwrap();
-    let mut vm = VirtualMachine::create(&h).unwrap();
-    let mut vcpu = Vcpu::create(&mut vm).unwrap();
+    let vm = VirtualMachine::create(&h).unwrap();
+    let mut vcpu = Vcpu::create(&vm).unwrap();
     let mut sregs = vcpu.get_sregs().unwrap();
     sregs.cr0 = 0x1;
     vcpu.set_sregs(&sregs).unwrap();
@@ -1111,10 +3360,939 @@ fn sreg_test() {
 #[test]
 fn reg_test() {
     let h = System::initialize().unwrap();
-    let mut vm = VirtualMachine::create(&h).unwrap();
-    let mut vcpu = Vcpu::create(&mut vm).unwrap();
+    let vm = VirtualMachine::create(&h).unwrap();
+    let mut vcpu = Vcpu::create(&vm).unwrap();
     let mut regs = vcpu.get_regs().unwrap();
     regs.rax = 0x1;
     vcpu.set_regs(&regs).unwrap();
     assert!(vcpu.get_regs().unwrap().rax == 0x1);
 }
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn run_test() {
+    let mut anon_mmap = Mmap::anonymous(16 * (1 << 12), Protection::ReadWrite)
+                            .unwrap();
+    let slice = unsafe { anon_mmap.as_mut_slice() };
+    // out 0x10, al; hlt
+    slice[0x1000] = 0xe6;
+    slice[0x1001] = 0x10;
+    slice[0x1002] = 0xf4;
+    let h = System::initialize().unwrap();
+    let vm = VirtualMachine::create(&h).unwrap();
+    vm.set_user_memory_region(0, anon_mmap, 0).unwrap();
+    let mut vcpu = Vcpu::create(&vm).unwrap();
+    let mut sregs = vcpu.get_sregs().unwrap();
+    sregs.cs.base = 0;
+    sregs.cs.selector = 0;
+    vcpu.set_sregs(&sregs).unwrap();
+    let mut regs = vcpu.get_regs().unwrap();
+    regs.rip = 0x1000;
+    regs.rax = 0x42;
+    regs.rflags = 0x2;
+    vcpu.set_regs(&regs).unwrap();
+    match vcpu.run().unwrap() {
+        VcpuExit::IoOut { port, size, data } => {
+            assert!(port == 0x10);
+            assert!(size == 1);
+            assert!(data == [0x42]);
+        }
+        exit => panic!("unexpected exit {:?}", exit),
+    }
+    match vcpu.run().unwrap() {
+        VcpuExit::Hlt => {}
+        exit => panic!("unexpected exit {:?}", exit),
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn io_data_test() {
+    let mut anon_mmap = Mmap::anonymous(16 * (1 << 12), Protection::ReadWrite)
+                            .unwrap();
+    let slice = unsafe { anon_mmap.as_mut_slice() };
+    // rep insb; hlt
+    slice[0x1000] = 0xf3;
+    slice[0x1001] = 0x6c;
+    slice[0x1002] = 0xf4;
+    let h = System::initialize().unwrap();
+    let vm = VirtualMachine::create(&h).unwrap();
+    vm.set_user_memory_region(0, anon_mmap, 0).unwrap();
+    let mut vcpu = Vcpu::create(&vm).unwrap();
+    let mut sregs = vcpu.get_sregs().unwrap();
+    sregs.cs.base = 0;
+    sregs.cs.selector = 0;
+    sregs.es.base = 0;
+    sregs.es.selector = 0;
+    vcpu.set_sregs(&sregs).unwrap();
+    let mut regs = vcpu.get_regs().unwrap();
+    regs.rip = 0x1000;
+    regs.rcx = 4;
+    regs.rdx = 0x10;
+    regs.rdi = 0x2000;
+    regs.rflags = 0x2;
+    vcpu.set_regs(&regs).unwrap();
+    match vcpu.run().unwrap() {
+        VcpuExit::IoIn { port, size, data } => {
+            assert!(port == 0x10);
+            assert!(size == 1);
+            assert!(data.len() == 4);
+        }
+        exit => panic!("unexpected exit {:?}", exit),
+    }
+    vcpu.io_data_mut().unwrap().copy_from_slice(&[1, 2, 3, 4]);
+    assert!(vcpu.io_data().unwrap() == [1, 2, 3, 4]);
+    match vcpu.run().unwrap() {
+        VcpuExit::Hlt => {}
+        exit => panic!("unexpected exit {:?}", exit),
+    }
+    assert!(vcpu.io_data().is_none());
+    assert!(vcpu.get_regs().unwrap().rdi == 0x2004);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn mmio_test() {
+    let mut anon_mmap = Mmap::anonymous(16 * (1 << 12), Protection::ReadWrite)
+                            .unwrap();
+    let slice = unsafe { anon_mmap.as_mut_slice() };
+    // mov ax, [0]; hlt
+    slice[0x1000] = 0xa1;
+    slice[0x1001] = 0x00;
+    slice[0x1002] = 0x00;
+    slice[0x1003] = 0xf4;
+    let h = System::initialize().unwrap();
+    let vm = VirtualMachine::create(&h).unwrap();
+    vm.set_user_memory_region(0, anon_mmap, 0).unwrap();
+    let mut vcpu = Vcpu::create(&vm).unwrap();
+    let mut sregs = vcpu.get_sregs().unwrap();
+    sregs.cs.base = 0;
+    sregs.cs.selector = 0;
+    // Point the data segment past the end of guest memory
+    sregs.ds.base = 0x20000;
+    sregs.ds.selector = 0x2000;
+    vcpu.set_sregs(&sregs).unwrap();
+    let mut regs = vcpu.get_regs().unwrap();
+    regs.rip = 0x1000;
+    regs.rflags = 0x2;
+    vcpu.set_regs(&regs).unwrap();
+    match vcpu.run().unwrap() {
+        VcpuExit::MmioRead { addr, data } => {
+            assert!(addr == 0x20000);
+            assert!(data.len() == 2);
+        }
+        exit => panic!("unexpected exit {:?}", exit),
+    }
+    assert!(vcpu.set_mmio_read_data(&[0x34]).is_err());
+    vcpu.set_mmio_read_data(&[0x34, 0x12]).unwrap();
+    match vcpu.run().unwrap() {
+        VcpuExit::Hlt => {}
+        exit => panic!("unexpected exit {:?}", exit),
+    }
+    assert!(vcpu.set_mmio_read_data(&[0x34, 0x12]).is_err());
+    assert!(vcpu.get_regs().unwrap().rax & 0xffff == 0x1234);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn vcpu_threads_test() {
+    let mut anon_mmap = Mmap::anonymous(8 * (1 << 12), Protection::ReadWrite)
+                            .unwrap();
+    let slice = unsafe { anon_mmap.as_mut_slice() };
+    // hlt
+    slice[0x1000] = 0xf4;
+    let h = System::initialize().unwrap();
+    let vm = VirtualMachine::create(&h).unwrap();
+    vm.set_user_memory_region(0, anon_mmap, 0).unwrap();
+    let mut threads = Vec::new();
+    for id in 0..2 {
+        let mut vcpu = vm.create_vcpu(id).unwrap();
+        threads.push(::std::thread::spawn(move || {
+            let mut sregs = vcpu.get_sregs().unwrap();
+            sregs.cs.base = 0;
+            sregs.cs.selector = 0;
+            vcpu.set_sregs(&sregs).unwrap();
+            let mut regs = vcpu.get_regs().unwrap();
+            regs.rip = 0x1000;
+            regs.rflags = 0x2;
+            vcpu.set_regs(&regs).unwrap();
+            match vcpu.run().unwrap() {
+                VcpuExit::Hlt => {}
+                exit => panic!("unexpected exit {:?}", exit),
+            }
+        }));
+    }
+    // The VM can still be configured while its vcpus run
+    let high = Mmap::anonymous(8 * (1 << 12), Protection::ReadWrite).unwrap();
+    vm.set_user_memory_region(0x100000, high, 0).unwrap();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}
+
+#[test]
+fn exit_reason_test() {
+    assert!(Exit::from(2) == Exit::Io);
+    assert!(Exit::from(29) == Exit::X86Rdmsr);
+    assert!(Exit::from(1000) == Exit::Unrecognized(1000));
+}
+
+#[test]
+fn error_test() {
+    let anon_mmap = Mmap::anonymous(16 * (1 << 12), Protection::ReadWrite)
+                        .unwrap();
+    let h = System::initialize().unwrap();
+    let vm = VirtualMachine::create(&h).unwrap();
+    // Guest physical addresses must be page aligned
+    match vm.set_user_memory_region(0x10, anon_mmap, 0) {
+        Err(e @ Error::SetUserMemoryRegion { .. }) => {
+            assert!(e.errno() == Some(libc::EINVAL));
+            assert!(e.to_string().starts_with("KVM_SET_USER_MEMORY_REGION"));
+        }
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn vcpu_ids_test() {
+    let h = System::initialize().unwrap();
+    let vm = VirtualMachine::create(&h).unwrap();
+    let a = vm.create_vcpu(0).unwrap();
+    let b = vm.create_vcpu(2).unwrap();
+    match vm.create_vcpu(2) {
+        Err(Error::CreateVcpu { id: 2, errno }) => {
+            assert!(errno == libc::EEXIST)
+        }
+        r => panic!("unexpected result {:?}", r),
+    }
+    assert!(vm.create_vcpu(h.max_vcpu_id()).is_err());
+    // The failures above do not consume ids
+    let c = Vcpu::create(&vm).unwrap();
+    assert!(a.id() == 0 && b.id() == 2 && c.id() == 1);
+}
+
+#[test]
+fn memory_slots_test() {
+    let low = Mmap::anonymous(8 * (1 << 12), Protection::ReadWrite).unwrap();
+    let high = Mmap::anonymous(8 * (1 << 12), Protection::ReadWrite).unwrap();
+    let h = System::initialize().unwrap();
+    let vm = VirtualMachine::create(&h).unwrap();
+    assert!(vm.set_user_memory_region(0, low, 0).unwrap() == 0);
+    match vm.set_user_memory_region(0x4000, high, 0) {
+        Err(Error::MemoryRegionOverlap { slot: 0, guest_phys_addr: 0 }) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+    let other = Mmap::anonymous(16 * (1 << 12), Protection::ReadWrite)
+                    .unwrap();
+    assert!(vm.set_user_memory_region(0x100000, other, 0).unwrap() == 1);
+    vm.modify_memory_region_flags(1, MEM_LOG_DIRTY_PAGES).unwrap();
+    vm.move_memory_region(1, 0x200000).unwrap();
+    assert!(vm.move_memory_region(1, 0x4000).is_err());
+    match vm.move_memory_region(1, !0xfff) {
+        Err(Error::InvalidArgument(_)) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+    let low = vm.remove_memory_region(0).unwrap();
+    assert!(low.len() == 8 * (1 << 12));
+    assert!(vm.remove_memory_region(0).is_err());
+    // Slot 0 is reused
+    assert!(vm.set_user_memory_region(0x10000, low, 0).unwrap() == 0);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn dirty_log_test() {
+    let mut anon_mmap = Mmap::anonymous(16 * (1 << 12), Protection::ReadWrite)
+                            .unwrap();
+    let slice = unsafe { anon_mmap.as_mut_slice() };
+    // mov [0x3000], al; hlt
+    slice[0x1000] = 0xa2;
+    slice[0x1001] = 0x00;
+    slice[0x1002] = 0x30;
+    slice[0x1003] = 0xf4;
+    let h = System::initialize().unwrap();
+    let vm = VirtualMachine::create(&h).unwrap();
+    let manual = vm.enable_manual_dirty_log_protect(false).is_ok();
+    let slot = vm.set_user_memory_region(0, anon_mmap, MEM_LOG_DIRTY_PAGES)
+                 .unwrap();
+    let mut vcpu = Vcpu::create(&vm).unwrap();
+    let mut sregs = vcpu.get_sregs().unwrap();
+    sregs.cs.base = 0;
+    sregs.cs.selector = 0;
+    sregs.ds.base = 0;
+    sregs.ds.selector = 0;
+    vcpu.set_sregs(&sregs).unwrap();
+    let mut regs = vcpu.get_regs().unwrap();
+    regs.rip = 0x1000;
+    regs.rax = 0x42;
+    regs.rflags = 0x2;
+    vcpu.set_regs(&regs).unwrap();
+    match vcpu.run().unwrap() {
+        VcpuExit::Hlt => {}
+        exit => panic!("unexpected exit {:?}", exit),
+    }
+    let bitmap = vm.get_dirty_log(slot).unwrap();
+    let page = 0x3000 / bitmap.page_size();
+    assert!(bitmap.is_dirty(page));
+    assert!(bitmap.pages().any(|p| p == page));
+    assert!(bitmap.addrs().any(|a| a == 0x3000));
+    assert!(bitmap.count() == bitmap.pages().count() as u64);
+    assert!(vm.get_dirty_log(slot + 1).is_err());
+    if manual {
+        // Pages stay dirty until they are cleared
+        assert!(vm.get_dirty_log(slot).unwrap().is_dirty(page));
+        assert!(vm.clear_dirty_log(&bitmap, 1, 1).is_err());
+        assert!(vm.clear_dirty_log(&bitmap, 64, !0).is_err());
+        vm.clear_dirty_log(&bitmap, 0, bitmap.num_pages()).unwrap();
+    }
+    assert!(!vm.get_dirty_log(slot).unwrap().is_dirty(page));
+}
+
+// Run with `--ignored` on hosts which support dirty rings
+#[cfg(target_arch = "x86_64")]
+#[test]
+#[ignore]
+fn dirty_ring_test() {
+    let mut anon_mmap = Mmap::anonymous(16 * (1 << 12), Protection::ReadWrite)
+                            .unwrap();
+    let slice = unsafe { anon_mmap.as_mut_slice() };
+    // mov [0x3000], al; hlt
+    slice[0x1000] = 0xa2;
+    slice[0x1001] = 0x00;
+    slice[0x1002] = 0x30;
+    slice[0x1003] = 0xf4;
+    let h = System::initialize().unwrap();
+    let vm = VirtualMachine::create(&h).unwrap();
+    assert!(vm.check_capability(Capability::DirtyLogRing) > 0,
+            "dirty rings are not supported");
+    assert!(vm.enable_dirty_ring(1000).is_err());
+    vm.enable_dirty_ring(1024).unwrap();
+    let slot = vm.set_user_memory_region(0, anon_mmap, MEM_LOG_DIRTY_PAGES)
+                 .unwrap();
+    assert!(vm.get_dirty_log(slot).is_err());
+    let mut vcpu = Vcpu::create(&vm).unwrap();
+    assert!(vm.enable_dirty_ring(1024).is_err());
+    let mut sregs = vcpu.get_sregs().unwrap();
+    sregs.cs.base = 0;
+    sregs.cs.selector = 0;
+    sregs.ds.base = 0;
+    sregs.ds.selector = 0;
+    vcpu.set_sregs(&sregs).unwrap();
+    let mut regs = vcpu.get_regs().unwrap();
+    regs.rip = 0x1000;
+    regs.rflags = 0x2;
+    vcpu.set_regs(&regs).unwrap();
+    match vcpu.run().unwrap() {
+        VcpuExit::Hlt => {}
+        exit => panic!("unexpected exit {:?}", exit),
+    }
+    let page = 0x3000 / page_size();
+    let entries: Vec<_> = vcpu.dirty_ring_entries().collect();
+    assert!(entries.contains(&(slot, page)));
+    assert!(vcpu.dirty_ring_entries().next().is_none());
+    assert!(vm.reset_dirty_rings().unwrap() == entries.len() as u32);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn fpu_test() {
+    for &val in &[0.0, -1.5, 3.0e-310, 1.0e300, f64::INFINITY] {
+        assert!(X87Register::from_f64(val).to_f64() == val);
+    }
+    assert!(X87Register::from_f64(1.0).sign_exponent == 0x3fff);
+    // x87 denormals are far below the f64 range; a pseudo-denormal must
+    // decode like the normal value with the same bits
+    let denormal = X87Register {
+        mantissa: 1 << 63,
+        sign_exponent: 0x8000,
+    };
+    let normal = X87Register {
+        mantissa: 1 << 63,
+        sign_exponent: 0x8001,
+    };
+    assert!(denormal.to_f64() == normal.to_f64());
+    assert!(denormal.to_f64().is_sign_negative());
+    for &val in &[5.0e-324, -f64::MIN_POSITIVE] {
+        let reg = X87Register::from_f64(val);
+        assert!(reg.sign_exponent & 0x7fff != 0);
+        assert!(reg.to_f64() == val);
+    }
+    let h = System::initialize().unwrap();
+    let vm = VirtualMachine::create(&h).unwrap();
+    let mut vcpu = Vcpu::create(&vm).unwrap();
+    let mut fpu = vcpu.get_fpu().unwrap();
+    fpu.fcw = 0x37f;
+    fpu.set_st(0, X87Register::from_f64(2.5));
+    fpu.set_xmm(3, 0x0123456789abcdef_fedcba9876543210);
+    vcpu.set_fpu(&fpu).unwrap();
+    let fpu = vcpu.get_fpu().unwrap();
+    assert!(fpu.fcw == 0x37f);
+    assert!(fpu.st(0).to_f64() == 2.5);
+    assert!(fpu.xmm(3) == 0x0123456789abcdef_fedcba9876543210);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn msrs_test() {
+    let h = System::initialize().unwrap();
+    let indices = h.get_msr_index_list().unwrap();
+    assert!(indices.contains(&MSR_IA32_SYSENTER_CS));
+    assert!(indices.contains(&MSR_IA32_CR_PAT));
+    if h.check_capability(Capability::GetMsrFeatures) != 0 {
+        let features = h.get_feature_msr_index_list().unwrap();
+        let msrs = h.get_msr_features(&features).unwrap();
+        assert!(msrs.entries().len() <= features.len());
+    }
+    let vm = VirtualMachine::create(&h).unwrap();
+    let mut vcpu = Vcpu::create(&vm).unwrap();
+    let pat = 0x0007040600070406;
+    let n = vcpu.set_msrs(&[(MSR_IA32_SYSENTER_CS, 0x10),
+                            (MSR_IA32_CR_PAT, pat)])
+                .unwrap();
+    assert!(n == 2);
+    let msrs = vcpu.get_msrs(&[MSR_IA32_SYSENTER_CS, MSR_IA32_CR_PAT])
+                   .unwrap();
+    assert!(msrs.entries().len() == 2);
+    assert!(msrs.get(MSR_IA32_SYSENTER_CS) == Some(0x10));
+    assert!(msrs.get(MSR_IA32_CR_PAT) == Some(pat));
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn lapic_state_test() {
+    let mut lapic = LapicState::default();
+    lapic.set_apic_id(ApicIdFormat::XApic, 3);
+    assert!(lapic.reg(APIC_ID) == 0x03000000);
+    assert!(lapic.apic_id(ApicIdFormat::XApic) == 3);
+    lapic.set_icr(0x0200000000004500);
+    assert!(lapic.reg(APIC_ICR) == 0x4500);
+    assert!(lapic.reg(APIC_ICR2) == 0x02000000);
+    let mut timer = LvtEntry(0x10000);
+    assert!(timer.masked());
+    timer.set_vector(0xec);
+    timer.set_masked(false);
+    timer.set_timer_mode(APIC_TIMER_PERIODIC);
+    lapic.set_lvt(Lvt::Timer, timer);
+    assert!(lapic.reg(0x320) == 0x200ec);
+    assert!(lapic.lvt(Lvt::Timer).timer_mode() == APIC_TIMER_PERIODIC);
+    lapic.set_timer_divide_config(0xb);
+    assert!(lapic.timer_divisor() == 1);
+    lapic.set_timer_divide_config(0x3);
+    assert!(lapic.timer_divisor() == 16);
+    let h = System::initialize().unwrap();
+    let vm = VirtualMachine::create(&h).unwrap();
+    let vcpu = Vcpu::create(&vm).unwrap();
+    // There is no in-kernel local APIC
+    assert!(vcpu.get_lapic().is_err());
+    assert!(vcpu.apic_id_format().unwrap() == ApicIdFormat::XApic);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn irqchip_test() {
+    let h = System::initialize().unwrap();
+    let vm = VirtualMachine::create(&h).unwrap();
+    vm.create_irqchip().unwrap();
+    let vcpu = Vcpu::create(&vm).unwrap();
+    let lapic = vcpu.get_lapic().unwrap();
+    assert!(lapic.apic_id(ApicIdFormat::XApic) == 0);
+    vm.set_irq_line(4, true).unwrap();
+    vm.set_irq_line(4, false).unwrap();
+    if vm.check_capability(Capability::IrqInjectStatus) != 0 {
+        vm.set_irq_line_status(4, true).unwrap();
+        vm.set_irq_line_status(4, false).unwrap();
+    }
+    let mut pic = match vm.get_irqchip(IrqchipId::PicMaster).unwrap() {
+        IrqchipState::PicMaster(pic) => pic,
+        state => panic!("unexpected state {:?}", state),
+    };
+    pic.imr = 0xfb;
+    vm.set_irqchip(&IrqchipState::PicMaster(pic)).unwrap();
+    match vm.get_irqchip(IrqchipId::PicMaster).unwrap() {
+        IrqchipState::PicMaster(pic) => assert!(pic.imr == 0xfb),
+        state => panic!("unexpected state {:?}", state),
+    }
+    match vm.get_irqchip(IrqchipId::Ioapic).unwrap() {
+        IrqchipState::Ioapic(ioapic) => {
+            assert!(ioapic.base_address == 0xfec00000)
+        }
+        state => panic!("unexpected state {:?}", state),
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn split_irqchip_test() {
+    fn write_reg(ioapic: &Ioapic, reg: u32, val: u32) -> bool {
+        let base = ioapic.base_address();
+        assert!(!ioapic.mmio_write(base, &[reg as u8, 0, 0, 0]).unwrap());
+        let data = [val as u8, (val >> 8) as u8, (val >> 16) as u8,
+                    (val >> 24) as u8];
+        ioapic.mmio_write(base + 0x10, &data).unwrap()
+    }
+    fn read_reg(ioapic: &Ioapic, reg: u32) -> u32 {
+        let base = ioapic.base_address();
+        let mut data = [0; 4];
+        assert!(!ioapic.mmio_write(base, &[reg as u8, 0, 0, 0]).unwrap());
+        ioapic.mmio_read(base + 0x10, &mut data);
+        data.iter().rev().fold(0, |val, &b| val << 8 | b as u32)
+    }
+    fn irr(vcpu: &Vcpu, vector: usize) -> bool {
+        let lapic = vcpu.get_lapic().unwrap();
+        lapic.reg(APIC_IRR + vector / 32 * 0x10) & (1 << (vector % 32)) != 0
+    }
+    let h = System::initialize().unwrap();
+    let vm = VirtualMachine::create(&h).unwrap();
+    if vm.check_capability(Capability::SplitIrqchip) == 0 {
+        return;
+    }
+    vm.enable_split_irqchip(IOAPIC_NUM_PINS).unwrap();
+    let mut vcpu = Vcpu::create(&vm).unwrap();
+    let ioapic = Ioapic::new(&vm);
+    // Level triggered interrupts wait for the local APIC to be enabled
+    assert!(write_reg(&ioapic, 0x10 + 2 * 6, 0x32 | 1 << 15));
+    ioapic.set_irq(6, true).unwrap();
+    assert!(read_reg(&ioapic, 0x10 + 2 * 6) & 1 << 14 == 0);
+    let mut lapic = vcpu.get_lapic().unwrap();
+    lapic.set_spiv(APIC_SPIV_APIC_ENABLED | 0xff);
+    vcpu.set_lapic(&lapic).unwrap();
+    ioapic.set_irq(6, true).unwrap();
+    assert!(read_reg(&ioapic, 0x10 + 2 * 6) & 1 << 14 != 0);
+    assert!(irr(&vcpu, 0x32));
+    assert!(ioapic.contains(IOAPIC_DEFAULT_BASE + 0x10));
+    assert!(!ioapic.contains(IOAPIC_DEFAULT_BASE - 0x10));
+    assert!(!ioapic.contains(!0));
+    assert!(read_reg(&ioapic, 0x01) == 0x170011);
+    // Accesses outside of the registers are ignored
+    let mut data = [0xff; 4];
+    ioapic.mmio_read(0, &mut data);
+    assert!(data == [0; 4]);
+    assert!(!ioapic.mmio_write(!0, &[0x01, 0, 0, 0]).unwrap());
+    match ioapic.set_irq(IOAPIC_NUM_PINS, true) {
+        Err(Error::InvalidArgument(_)) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+    // Pin 4 is edge triggered and starts out masked
+    ioapic.set_irq(4, true).unwrap();
+    ioapic.set_irq(4, false).unwrap();
+    assert!(!irr(&vcpu, 0x30));
+    assert!(write_reg(&ioapic, 0x10 + 2 * 4, 0x30));
+    assert!(irr(&vcpu, 0x30));
+    // Masking a pin leaves its MSI unchanged
+    assert!(!write_reg(&ioapic, 0x10 + 2 * 4, 0x30 | 1 << 16));
+    // Pin 5 is level triggered
+    assert!(write_reg(&ioapic, 0x10 + 2 * 5, 0x31 | 1 << 15));
+    ioapic.set_irq(5, true).unwrap();
+    assert!(irr(&vcpu, 0x31));
+    assert!(read_reg(&ioapic, 0x10 + 2 * 5) & 1 << 14 != 0);
+    ioapic.set_irq(5, false).unwrap();
+    ioapic.end_of_interrupt(0x31).unwrap();
+    assert!(read_reg(&ioapic, 0x10 + 2 * 5) & 1 << 14 == 0);
+    let mut table = GsiRoutingTable::new();
+    ioapic.add_msi_routes(&mut table);
+    assert!(table.len() == IOAPIC_NUM_PINS as usize);
+    vm.set_gsi_routing(&table).unwrap();
+    let entry = ioapic.state().redirtbl[5];
+    assert!(entry.vector() == 0x31);
+    assert!(entry.level_triggered() && !entry.remote_irr() && !entry.masked());
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn ioapic_eoi_test() {
+    let mut anon_mmap = Mmap::anonymous(16 * (1 << 12), Protection::ReadWrite)
+                            .unwrap();
+    let slice = unsafe { anon_mmap.as_mut_slice() };
+    // Real mode vector 0x31 points to 0000:2000
+    slice[0xc4] = 0x00;
+    slice[0xc5] = 0x20;
+    // sti; hlt; jmp -3
+    slice[0x1000] = 0xfb;
+    slice[0x1001] = 0xf4;
+    slice[0x1002] = 0xeb;
+    slice[0x1003] = 0xfd;
+    // mov [0xb0], eax; out 0x11, al; hlt
+    slice[0x2000] = 0x66;
+    slice[0x2001] = 0xa3;
+    slice[0x2002] = 0xb0;
+    slice[0x2003] = 0x00;
+    slice[0x2004] = 0xe6;
+    slice[0x2005] = 0x11;
+    slice[0x2006] = 0xf4;
+    let h = System::initialize().unwrap();
+    let vm = VirtualMachine::create(&h).unwrap();
+    vm.enable_split_irqchip(IOAPIC_NUM_PINS).unwrap();
+    vm.set_user_memory_region(0, anon_mmap, 0).unwrap();
+    let mut vcpu = Vcpu::create(&vm).unwrap();
+    let mut lapic = vcpu.get_lapic().unwrap();
+    lapic.set_spiv(APIC_SPIV_APIC_ENABLED | 0xff);
+    vcpu.set_lapic(&lapic).unwrap();
+    let mut sregs = vcpu.get_sregs().unwrap();
+    sregs.cs.base = 0;
+    sregs.cs.selector = 0;
+    // The handler reaches the local APIC through ds
+    sregs.ds.base = 0xfee00000;
+    vcpu.set_sregs(&sregs).unwrap();
+    let mut regs = vcpu.get_regs().unwrap();
+    regs.rip = 0x1000;
+    regs.rflags = 0x2;
+    vcpu.set_regs(&regs).unwrap();
+    // Route pin 5 to vector 0x31, level triggered
+    let ioapic = Ioapic::new(&vm);
+    let base = ioapic.base_address();
+    ioapic.mmio_write(base, &[0x10 + 2 * 5, 0, 0, 0]).unwrap();
+    assert!(ioapic.mmio_write(base + 0x10, &[0x31, 0x80, 0, 0]).unwrap());
+    let mut table = GsiRoutingTable::new();
+    ioapic.add_msi_routes(&mut table);
+    vm.set_gsi_routing(&table).unwrap();
+    ioapic.set_irq(5, true).unwrap();
+    ioapic.set_irq(5, false).unwrap();
+    assert!(ioapic.state().redirtbl[5].remote_irr());
+    // Hosts which emulate real mode only report the EOI after the handler's
+    // I/O exit
+    let (mut eoi, mut handled) = (false, false);
+    while !eoi || !handled {
+        match vcpu.run().unwrap() {
+            VcpuExit::IoapicEoi { vector } if !eoi => {
+                assert!(vector == 0x31);
+                ioapic.end_of_interrupt(vector).unwrap();
+                eoi = true;
+            }
+            VcpuExit::IoOut { port: 0x11, .. } if !handled => handled = true,
+            exit => panic!("unexpected exit {:?}", exit),
+        }
+    }
+    assert!(!ioapic.state().redirtbl[5].remote_irr());
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn redirection_entry_test() {
+    assert!(size_of::<IoapicState>() == 216);
+    let mut entry = RedirectionEntry::default();
+    entry.set_vector(0x41);
+    entry.set_delivery_mode(APIC_DM_LOWEST);
+    entry.set_logical_destination(true);
+    entry.set_active_low(true);
+    entry.set_level_triggered(true);
+    entry.set_masked(true);
+    entry.set_destination(0x3);
+    assert!(entry.0 == 0x0300_0000_0001_a941);
+    assert!(entry.vector() == 0x41);
+    assert!(entry.delivery_mode() == APIC_DM_LOWEST);
+    assert!(!entry.send_pending() && !entry.remote_irr());
+    entry.set_masked(false);
+    assert!(!entry.masked() && entry.destination() == 3);
+    let h = System::initialize().unwrap();
+    let vm = VirtualMachine::create(&h).unwrap();
+    vm.create_irqchip().unwrap();
+    let mut ioapic = match vm.get_irqchip(IrqchipId::Ioapic).unwrap() {
+        IrqchipState::Ioapic(ioapic) => ioapic,
+        state => panic!("unexpected state {:?}", state),
+    };
+    assert!(ioapic.redirtbl.iter().all(|e| e.masked()));
+    ioapic.redirtbl[4] = entry;
+    vm.set_irqchip(&IrqchipState::Ioapic(ioapic)).unwrap();
+    match vm.get_irqchip(IrqchipId::Ioapic).unwrap() {
+        IrqchipState::Ioapic(ioapic) => assert!(ioapic.redirtbl[4] == entry),
+        state => panic!("unexpected state {:?}", state),
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn pit_test() {
+    let h = System::initialize().unwrap();
+    let vm = VirtualMachine::create(&h).unwrap();
+    vm.create_irqchip().unwrap();
+    vm.create_pit2(PIT_SPEAKER_DUMMY).unwrap();
+    let mut pit = vm.get_pit2().unwrap();
+    pit.channels[2].count = 0x1234;
+    pit.channels[2].mode = 2;
+    pit.flags = PIT_FLAGS_SPEAKER_DATA_ON;
+    vm.set_pit2(&pit).unwrap();
+    let pit = vm.get_pit2().unwrap();
+    assert!(pit.channels[2].count == 0x1234);
+    assert!(pit.channels[2].mode == 2);
+    assert!(pit.flags & PIT_FLAGS_SPEAKER_DATA_ON != 0);
+    if vm.check_capability(Capability::ReinjectControl) != 0 {
+        vm.set_pit_reinject(false).unwrap();
+    }
+}
+
+#[test]
+fn eventfd_test() {
+    let fd = EventFd::new_nonblocking().unwrap();
+    assert!(fd.read().unwrap_err().errno() == Some(libc::EAGAIN));
+    fd.write(2).unwrap();
+    fd.try_clone().unwrap().write(3).unwrap();
+    assert!(fd.read().unwrap() == 5);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn irqfd_test() {
+    let h = System::initialize().unwrap();
+    let vm = VirtualMachine::create(&h).unwrap();
+    vm.create_irqchip().unwrap();
+    let fd = EventFd::new().unwrap();
+    vm.register_irqfd(&fd, 4).unwrap();
+    // The same eventfd cannot be bound twice
+    assert!(vm.register_irqfd(&fd, 4).is_err());
+    fd.write(1).unwrap();
+    // The interrupt is injected asynchronously
+    let mut raised = false;
+    for _ in 0..1000 {
+        match vm.get_irqchip(IrqchipId::PicMaster).unwrap() {
+            IrqchipState::PicMaster(pic) if pic.irr & (1 << 4) != 0 => {
+                raised = true;
+                break;
+            }
+            _ => ::std::thread::sleep(::std::time::Duration::from_millis(1)),
+        }
+    }
+    assert!(raised);
+    vm.unregister_irqfd(&fd, 4).unwrap();
+    if vm.check_capability(Capability::IrqfdResample) != 0 {
+        let resample = EventFd::new().unwrap();
+        vm.register_irqfd_with_resample(&fd, &resample, 5).unwrap();
+        vm.unregister_irqfd(&fd, 5).unwrap();
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn ioeventfd_test() {
+    let mut anon_mmap = Mmap::anonymous(16 * (1 << 12), Protection::ReadWrite)
+                            .unwrap();
+    let slice = unsafe { anon_mmap.as_mut_slice() };
+    // out 0x10, al; out 0x10, al; hlt
+    slice[0x1000] = 0xe6;
+    slice[0x1001] = 0x10;
+    slice[0x1002] = 0xe6;
+    slice[0x1003] = 0x10;
+    slice[0x1004] = 0xf4;
+    let h = System::initialize().unwrap();
+    let vm = VirtualMachine::create(&h).unwrap();
+    vm.set_user_memory_region(0, anon_mmap, 0).unwrap();
+    let fd = EventFd::new_nonblocking().unwrap();
+    let other = EventFd::new_nonblocking().unwrap();
+    vm.register_ioevent(IoEventAddress::Pio(0x10), 1, Some(0x42), &fd)
+      .unwrap();
+    vm.register_ioevent(IoEventAddress::Mmio(0x10000), 4, None, &other)
+      .unwrap();
+    let mut vcpu = Vcpu::create(&vm).unwrap();
+    let mut sregs = vcpu.get_sregs().unwrap();
+    sregs.cs.base = 0;
+    sregs.cs.selector = 0;
+    vcpu.set_sregs(&sregs).unwrap();
+    let mut regs = vcpu.get_regs().unwrap();
+    regs.rip = 0x1000;
+    regs.rax = 0x42;
+    regs.rflags = 0x2;
+    vcpu.set_regs(&regs).unwrap();
+    match vcpu.run().unwrap() {
+        VcpuExit::Hlt => {}
+        exit => panic!("unexpected exit {:?}", exit),
+    }
+    assert!(fd.read().unwrap() == 2);
+    assert!(other.read().is_err());
+    vm.unregister_ioevent(IoEventAddress::Pio(0x10), 1, Some(0x42), &fd)
+      .unwrap();
+    assert!(vm.unregister_ioevent(IoEventAddress::Pio(0x10), 1, None, &fd)
+              .is_err());
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn gsi_routing_test() {
+    let mut table = GsiRoutingTable::new();
+    table.add_default_irqchip_routes()
+         .add_msi(30, 0xfee00000, 0x41, None)
+         .add_msi(31, 0x1_fee01000, 0x42, Some(7));
+    assert!(table.len() == 16 + 24 + 2);
+    let msi = table.entries[table.len() - 1];
+    assert!(msi.flags == MSI_VALID_DEVID);
+    assert!(msi.u[..4] == [0xfee01000, 1, 0x42, 7]);
+    let h = System::initialize().unwrap();
+    let vm = VirtualMachine::create(&h).unwrap();
+    vm.create_irqchip().unwrap();
+    let mut table = GsiRoutingTable::new();
+    table.add_default_irqchip_routes()
+         .add_msi(30, 0xfee00000, 0x41, None);
+    vm.set_gsi_routing(&table).unwrap();
+    let fd = EventFd::new().unwrap();
+    vm.register_irqfd(&fd, 30).unwrap();
+    let max_routes = vm.check_capability(Capability::IrqRouting) as u32;
+    table.add_msi(max_routes, 0xfee00000, 0x42, None);
+    match vm.set_gsi_routing(&table) {
+        Err(Error::InvalidArgument(_)) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn signal_msi_test() {
+    let h = System::initialize().unwrap();
+    let vm = VirtualMachine::create(&h).unwrap();
+    if vm.check_capability(Capability::SignalMsi) == 0 {
+        return;
+    }
+    vm.create_irqchip().unwrap();
+    let mut vcpu = Vcpu::create(&vm).unwrap();
+    // Fixed interrupts are not accepted while the APIC is disabled
+    assert!(vm.signal_msi(0xfee00000, 0x40, None).unwrap() ==
+            IrqStatus::Blocked);
+    let mut lapic = vcpu.get_lapic().unwrap();
+    lapic.set_spiv(APIC_SPIV_APIC_ENABLED | 0xff);
+    vcpu.set_lapic(&lapic).unwrap();
+    assert!(vm.signal_msi(0xfee00000, 0x41, None).unwrap() ==
+            IrqStatus::Delivered(1));
+    let lapic = vcpu.get_lapic().unwrap();
+    assert!(lapic.reg(APIC_IRR + 0x20) & 1 << 1 != 0);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn interrupt_window_test() {
+    let mut anon_mmap = Mmap::anonymous(16 * (1 << 12), Protection::ReadWrite)
+                            .unwrap();
+    let slice = unsafe { anon_mmap.as_mut_slice() };
+    // Real mode vector 0x20 points to 0000:2000
+    slice[0x80] = 0x00;
+    slice[0x81] = 0x20;
+    // out 0x10, al; sti; hlt; jmp -3
+    slice[0x1000] = 0xe6;
+    slice[0x1001] = 0x10;
+    slice[0x1002] = 0xfb;
+    slice[0x1003] = 0xf4;
+    slice[0x1004] = 0xeb;
+    slice[0x1005] = 0xfd;
+    // out 0x11, al; hlt
+    slice[0x2000] = 0xe6;
+    slice[0x2001] = 0x11;
+    slice[0x2002] = 0xf4;
+    let h = System::initialize().unwrap();
+    let vm = VirtualMachine::create(&h).unwrap();
+    vm.set_user_memory_region(0, anon_mmap, 0).unwrap();
+    let mut vcpu = Vcpu::create(&vm).unwrap();
+    let mut sregs = vcpu.get_sregs().unwrap();
+    sregs.cs.base = 0;
+    sregs.cs.selector = 0;
+    vcpu.set_sregs(&sregs).unwrap();
+    let mut regs = vcpu.get_regs().unwrap();
+    regs.rip = 0x1000;
+    regs.rflags = 0x2;
+    vcpu.set_regs(&regs).unwrap();
+    match vcpu.run().unwrap() {
+        VcpuExit::IoOut { port: 0x10, .. } => {}
+        exit => panic!("unexpected exit {:?}", exit),
+    }
+    assert!(!vcpu.interrupts_enabled());
+    assert!(!vcpu.ready_for_interrupt_injection());
+    vcpu.set_request_interrupt_window(true);
+    // The window may open before or after the guest halts
+    while !vcpu.ready_for_interrupt_injection() {
+        match vcpu.run().unwrap() {
+            VcpuExit::IrqWindowOpen | VcpuExit::Hlt => {}
+            exit => panic!("unexpected exit {:?}", exit),
+        }
+    }
+    assert!(vcpu.interrupts_enabled());
+    vcpu.set_request_interrupt_window(false);
+    vcpu.inject_interrupt(0x20).unwrap();
+    match vcpu.run().unwrap() {
+        VcpuExit::IoOut { port: 0x11, .. } => {}
+        exit => panic!("unexpected exit {:?}", exit),
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn nmi_test() {
+    let mut anon_mmap = Mmap::anonymous(16 * (1 << 12), Protection::ReadWrite)
+                            .unwrap();
+    let slice = unsafe { anon_mmap.as_mut_slice() };
+    // Real mode vector 2 points to 0000:2000
+    slice[0x08] = 0x00;
+    slice[0x09] = 0x20;
+    // hlt; jmp -3
+    slice[0x1000] = 0xf4;
+    slice[0x1001] = 0xeb;
+    slice[0x1002] = 0xfd;
+    // out 0x11, al; hlt
+    slice[0x2000] = 0xe6;
+    slice[0x2001] = 0x11;
+    slice[0x2002] = 0xf4;
+    let h = System::initialize().unwrap();
+    let vm = VirtualMachine::create(&h).unwrap();
+    vm.set_user_memory_region(0, anon_mmap, 0).unwrap();
+    let mut vcpu = Vcpu::create(&vm).unwrap();
+    let mut sregs = vcpu.get_sregs().unwrap();
+    sregs.cs.base = 0;
+    sregs.cs.selector = 0;
+    vcpu.set_sregs(&sregs).unwrap();
+    let mut regs = vcpu.get_regs().unwrap();
+    regs.rip = 0x1000;
+    regs.rflags = 0x2;
+    vcpu.set_regs(&regs).unwrap();
+    match vcpu.run().unwrap() {
+        VcpuExit::Hlt => {}
+        exit => panic!("unexpected exit {:?}", exit),
+    }
+    // NMIs are delivered even with interrupts disabled
+    vcpu.inject_nmi().unwrap();
+    match vcpu.run().unwrap() {
+        VcpuExit::IoOut { port: 0x11, .. } => {}
+        exit => panic!("unexpected exit {:?}", exit),
+    }
+    if vm.check_capability(Capability::X86Smm) != 0 {
+        vcpu.inject_smi().unwrap();
+        let events = vcpu.get_vcpu_events().unwrap();
+        assert!(events.flags & VCPUEVENT_VALID_SMM != 0);
+        assert!(events.smi.pending != 0 && events.smi.smm == 0);
+    } else {
+        match vcpu.inject_smi() {
+            Err(Error::InvalidArgument(_)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn vcpu_events_test() {
+    assert!(size_of::<VcpuEvents>() == 64);
+    let h = System::initialize().unwrap();
+    let vm = VirtualMachine::create(&h).unwrap();
+    let payload = vm.check_capability(Capability::ExceptionPayload) != 0;
+    if payload {
+        vm.enable_exception_payload().unwrap();
+    }
+    let mut vcpu = Vcpu::create(&vm).unwrap();
+    vcpu.inject_nmi().unwrap();
+    let mut events = vcpu.get_vcpu_events().unwrap();
+    assert!(events.flags & VCPUEVENT_VALID_NMI_PENDING != 0);
+    assert!(events.nmi.pending == 1);
+    assert!(payload == (events.flags & VCPUEVENT_VALID_PAYLOAD != 0));
+    events.nmi.pending = 0;
+    if payload {
+        // A page fault which has not yet updated CR2
+        events.exception.pending = 1;
+        events.exception.nr = 14;
+        events.exception.has_error_code = 1;
+        events.exception.error_code = 2;
+        events.exception_has_payload = 1;
+        events.exception_payload = 0xdead000;
+    }
+    vcpu.set_vcpu_events(&events).unwrap();
+    let events = vcpu.get_vcpu_events().unwrap();
+    assert!(events.nmi.pending == 0);
+    if payload {
+        assert!(events.exception.pending == 1);
+        assert!(events.exception.nr == 14);
+        assert!(events.exception_has_payload == 1);
+        assert!(events.exception_payload == 0xdead000);
+    }
+}
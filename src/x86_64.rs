@@ -10,7 +10,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 use libc::{c_void, calloc, free};
-use std::{mem, slice};
+use std::{f64, slice};
+use std::mem::size_of;
 use std::ops::{Deref, DerefMut};
 
 #[repr(C)]
@@ -44,14 +45,14 @@ impl ::std::default::Default for PicState {
     }
 }
 #[repr(C)]
-#[derive(Copy)]
+#[derive(Copy, Debug)]
 pub struct IoapicState {
     pub base_address: u64,
     pub ioregsel: u32,
     pub id: u32,
     pub irr: u32,
     pub pad: u32,
-    pub redirtbl: [Union_Unnamed3; 24usize],
+    pub redirtbl: [RedirectionEntry; 24usize],
 }
 impl ::std::clone::Clone for IoapicState {
     fn clone(&self) -> Self {
@@ -63,56 +64,129 @@ impl ::std::default::Default for IoapicState {
         unsafe { ::std::mem::zeroed() }
     }
 }
+/// An IOAPIC redirection table entry
 #[repr(C)]
-#[derive(Copy)]
-pub struct Union_Unnamed3 {
-    pub _bindgen_data_: [u64; 1usize],
-}
-impl Union_Unnamed3 {
-    pub unsafe fn bits(&mut self) -> *mut u64 {
-        let raw: *mut u8 = ::std::mem::transmute(&self._bindgen_data_);
-        ::std::mem::transmute(raw.offset(0))
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct RedirectionEntry(pub u64);
+
+impl RedirectionEntry {
+    fn bit(&self, bit: u32) -> bool {
+        self.0 & (1 << bit) != 0
+    }
+    fn set_bit(&mut self, bit: u32, val: bool) {
+        if val {
+            self.0 |= 1 << bit;
+        } else {
+            self.0 &= !(1 << bit);
+        }
     }
-    pub unsafe fn fields(&mut self) -> *mut Struct_Unnamed4 {
-        let raw: *mut u8 = ::std::mem::transmute(&self._bindgen_data_);
-        ::std::mem::transmute(raw.offset(0))
+    /// Interrupt vector
+    pub fn vector(&self) -> u8 {
+        self.0 as u8
     }
-}
-impl ::std::clone::Clone for Union_Unnamed3 {
-    fn clone(&self) -> Self {
-        *self
+    /// Set the interrupt vector
+    pub fn set_vector(&mut self, vector: u8) {
+        self.0 = (self.0 & !0xff) | vector as u64;
     }
-}
-impl ::std::default::Default for Union_Unnamed3 {
-    fn default() -> Self {
-        unsafe { ::std::mem::zeroed() }
+    /// One of the `APIC_DM_*` constants
+    pub fn delivery_mode(&self) -> u32 {
+        ((self.0 >> 8) & 0x7) as u32
     }
-}
-#[repr(C)]
-#[derive(Copy)]
-pub struct Struct_Unnamed4 {
-    pub vector: u8,
-    pub _bindgen_bitfield_1_: u8,
-    pub _bindgen_bitfield_2_: u8,
-    pub _bindgen_bitfield_3_: u8,
-    pub _bindgen_bitfield_4_: u8,
-    pub _bindgen_bitfield_5_: u8,
-    pub _bindgen_bitfield_6_: u8,
-    pub _bindgen_bitfield_7_: u8,
-    pub _bindgen_bitfield_8_: u8,
-    pub reserved: [u8; 4usize],
-    pub dest_id: u8,
-}
-impl ::std::clone::Clone for Struct_Unnamed4 {
-    fn clone(&self) -> Self {
-        *self
+    /// Set the delivery mode to one of the `APIC_DM_*` constants
+    pub fn set_delivery_mode(&mut self, mode: u32) {
+        self.0 = (self.0 & !(0x7 << 8)) | ((mode & 0x7) as u64) << 8;
+    }
+    /// Whether the destination is a logical rather than physical APIC ID
+    pub fn logical_destination(&self) -> bool {
+        self.bit(11)
+    }
+    /// Set whether the destination is a logical APIC ID
+    pub fn set_logical_destination(&mut self, logical: bool) {
+        self.set_bit(11, logical)
+    }
+    /// Whether an interrupt is pending delivery
+    pub fn send_pending(&self) -> bool {
+        self.bit(12)
+    }
+    /// Set whether an interrupt is pending delivery
+    pub fn set_send_pending(&mut self, pending: bool) {
+        self.set_bit(12, pending)
+    }
+    /// Whether the interrupt input is active low
+    pub fn active_low(&self) -> bool {
+        self.bit(13)
+    }
+    /// Set whether the interrupt input is active low
+    pub fn set_active_low(&mut self, active_low: bool) {
+        self.set_bit(13, active_low)
+    }
+    /// Whether a level triggered interrupt was accepted and awaits an EOI
+    pub fn remote_irr(&self) -> bool {
+        self.bit(14)
+    }
+    /// Set whether a level triggered interrupt awaits an EOI
+    pub fn set_remote_irr(&mut self, remote_irr: bool) {
+        self.set_bit(14, remote_irr)
+    }
+    /// Whether the interrupt is level triggered
+    pub fn level_triggered(&self) -> bool {
+        self.bit(15)
+    }
+    /// Set whether the interrupt is level triggered
+    pub fn set_level_triggered(&mut self, level: bool) {
+        self.set_bit(15, level)
+    }
+    /// Whether the interrupt is masked
+    pub fn masked(&self) -> bool {
+        self.bit(16)
+    }
+    /// Mask or unmask the interrupt
+    pub fn set_masked(&mut self, masked: bool) {
+        self.set_bit(16, masked)
+    }
+    /// Destination APIC ID or logical destination
+    pub fn destination(&self) -> u8 {
+        (self.0 >> 56) as u8
+    }
+    /// Set the destination
+    pub fn set_destination(&mut self, dest: u8) {
+        self.0 = (self.0 & !(0xff << 56)) | (dest as u64) << 56;
     }
 }
-impl ::std::default::Default for Struct_Unnamed4 {
-    fn default() -> Self {
-        unsafe { ::std::mem::zeroed() }
+
+/// An in-kernel interrupt controller
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum IrqchipId {
+    /// The master 8259 PIC
+    PicMaster = 0,
+    /// The slave 8259 PIC
+    PicSlave = 1,
+    /// The IOAPIC
+    Ioapic = 2,
+}
+
+/// State of an in-kernel interrupt controller
+#[derive(Clone, Copy, Debug)]
+pub enum IrqchipState {
+    /// The master 8259 PIC
+    PicMaster(PicState),
+    /// The slave 8259 PIC
+    PicSlave(PicState),
+    /// The IOAPIC
+    Ioapic(IoapicState),
+}
+
+impl IrqchipState {
+    /// The interrupt controller this is the state of
+    pub fn id(&self) -> IrqchipId {
+        match *self {
+            IrqchipState::PicMaster(_) => IrqchipId::PicMaster,
+            IrqchipState::PicSlave(_) => IrqchipId::PicSlave,
+            IrqchipState::Ioapic(_) => IrqchipId::Ioapic,
+        }
     }
 }
+
 #[repr(C)]
 #[derive(Copy)]
 pub struct Regs {
@@ -160,6 +234,254 @@ impl ::std::default::Default for LapicState {
         unsafe { ::std::mem::zeroed() }
     }
 }
+
+/// Offset of the local APIC ID register
+pub const APIC_ID: usize = 0x20;
+/// Offset of the local APIC version register
+pub const APIC_VERSION: usize = 0x30;
+/// Offset of the task priority register
+pub const APIC_TPR: usize = 0x80;
+/// Offset of the processor priority register
+pub const APIC_PPR: usize = 0xa0;
+/// Offset of the end of interrupt register
+pub const APIC_EOI: usize = 0xb0;
+/// Offset of the logical destination register
+pub const APIC_LDR: usize = 0xd0;
+/// Offset of the destination format register
+pub const APIC_DFR: usize = 0xe0;
+/// Offset of the spurious interrupt vector register
+pub const APIC_SPIV: usize = 0xf0;
+/// Offset of the first in-service register
+pub const APIC_ISR: usize = 0x100;
+/// Offset of the first trigger mode register
+pub const APIC_TMR: usize = 0x180;
+/// Offset of the first interrupt request register
+pub const APIC_IRR: usize = 0x200;
+/// Offset of the error status register
+pub const APIC_ESR: usize = 0x280;
+/// Offset of the low half of the interrupt command register
+pub const APIC_ICR: usize = 0x300;
+/// Offset of the high half of the interrupt command register
+pub const APIC_ICR2: usize = 0x310;
+/// Offset of the timer initial count register
+pub const APIC_TMICT: usize = 0x380;
+/// Offset of the timer current count register
+pub const APIC_TMCCT: usize = 0x390;
+/// Offset of the timer divide configuration register
+pub const APIC_TDCR: usize = 0x3e0;
+
+/// Software enable bit of the spurious interrupt vector register
+pub const APIC_SPIV_APIC_ENABLED: u32 = 1 << 8;
+
+/// Format of the local APIC ID register in a `LapicState`
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ApicIdFormat {
+    /// The 8-bit xAPIC ID in bits 24-31
+    XApic,
+    /// The full 32-bit x2APIC ID
+    X2Apic,
+}
+
+/// A local vector table entry
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Lvt {
+    /// Corrected machine check interrupt
+    Cmci,
+    /// Local APIC timer
+    Timer,
+    /// Thermal sensor
+    Thermal,
+    /// Performance monitoring counters
+    PerfCounter,
+    /// LINT0 pin
+    Lint0,
+    /// LINT1 pin
+    Lint1,
+    /// Internal APIC errors
+    Error,
+}
+
+impl Lvt {
+    /// Offset of the register in a `LapicState`
+    pub fn offset(&self) -> usize {
+        match *self {
+            Lvt::Cmci => 0x2f0,
+            Lvt::Timer => 0x320,
+            Lvt::Thermal => 0x330,
+            Lvt::PerfCounter => 0x340,
+            Lvt::Lint0 => 0x350,
+            Lvt::Lint1 => 0x360,
+            Lvt::Error => 0x370,
+        }
+    }
+}
+
+/// Delivery mode of a fixed interrupt
+pub const APIC_DM_FIXED: u32 = 0;
+/// Delivery mode of an interrupt to the lowest priority destination
+pub const APIC_DM_LOWEST: u32 = 1;
+/// Delivery mode of a system management interrupt
+pub const APIC_DM_SMI: u32 = 2;
+/// Delivery mode of a non-maskable interrupt
+pub const APIC_DM_NMI: u32 = 4;
+/// Delivery mode of an INIT
+pub const APIC_DM_INIT: u32 = 5;
+/// Delivery mode of an external (8259 compatible) interrupt
+pub const APIC_DM_EXTINT: u32 = 7;
+
+/// Timer mode which fires once
+pub const APIC_TIMER_ONESHOT: u32 = 0;
+/// Timer mode which fires periodically
+pub const APIC_TIMER_PERIODIC: u32 = 1;
+/// Timer mode which fires at a TSC deadline
+pub const APIC_TIMER_TSC_DEADLINE: u32 = 2;
+
+/// The value of a local vector table register
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct LvtEntry(pub u32);
+
+impl LvtEntry {
+    /// Interrupt vector
+    pub fn vector(&self) -> u8 {
+        self.0 as u8
+    }
+    /// Set the interrupt vector
+    pub fn set_vector(&mut self, vector: u8) {
+        self.0 = (self.0 & !0xff) | vector as u32;
+    }
+    /// One of the `APIC_DM_*` constants
+    pub fn delivery_mode(&self) -> u32 {
+        (self.0 >> 8) & 0x7
+    }
+    /// Set the delivery mode to one of the `APIC_DM_*` constants
+    pub fn set_delivery_mode(&mut self, mode: u32) {
+        self.0 = (self.0 & !(0x7 << 8)) | (mode & 0x7) << 8;
+    }
+    /// Whether an interrupt is pending delivery
+    pub fn send_pending(&self) -> bool {
+        self.0 & (1 << 12) != 0
+    }
+    /// Whether the interrupt input is active low
+    pub fn active_low(&self) -> bool {
+        self.0 & (1 << 13) != 0
+    }
+    /// Whether the interrupt is level triggered
+    pub fn level_triggered(&self) -> bool {
+        self.0 & (1 << 15) != 0
+    }
+    /// Whether the interrupt is masked
+    pub fn masked(&self) -> bool {
+        self.0 & (1 << 16) != 0
+    }
+    /// Mask or unmask the interrupt
+    pub fn set_masked(&mut self, masked: bool) {
+        if masked {
+            self.0 |= 1 << 16;
+        } else {
+            self.0 &= !(1 << 16);
+        }
+    }
+    /// One of the `APIC_TIMER_*` constants, for `Lvt::Timer`
+    pub fn timer_mode(&self) -> u32 {
+        (self.0 >> 17) & 0x3
+    }
+    /// Set the timer mode to one of the `APIC_TIMER_*` constants
+    pub fn set_timer_mode(&mut self, mode: u32) {
+        self.0 = (self.0 & !(0x3 << 17)) | (mode & 0x3) << 17;
+    }
+}
+
+impl LapicState {
+    /// Read the 32-bit register at `offset`
+    pub fn reg(&self, offset: usize) -> u32 {
+        let mut val = 0;
+        for (i, &b) in self.regs[offset..offset + 4].iter().enumerate() {
+            val |= (b as u8 as u32) << (i * 8);
+        }
+        val
+    }
+    /// Write the 32-bit register at `offset`
+    pub fn set_reg(&mut self, offset: usize, val: u32) {
+        for (i, b) in self.regs[offset..offset + 4].iter_mut().enumerate() {
+            *b = (val >> (i * 8)) as u8 as ::libc::c_char;
+        }
+    }
+    /// Local APIC ID
+    ///
+    /// Use `Vcpu::apic_id_format` to determine the format.
+    pub fn apic_id(&self, format: ApicIdFormat) -> u32 {
+        match format {
+            ApicIdFormat::XApic => self.reg(APIC_ID) >> 24,
+            ApicIdFormat::X2Apic => self.reg(APIC_ID),
+        }
+    }
+    /// Set the local APIC ID
+    pub fn set_apic_id(&mut self, format: ApicIdFormat, id: u32) {
+        match format {
+            ApicIdFormat::XApic => self.set_reg(APIC_ID, id << 24),
+            ApicIdFormat::X2Apic => self.set_reg(APIC_ID, id),
+        }
+    }
+    /// Task priority
+    pub fn tpr(&self) -> u32 {
+        self.reg(APIC_TPR)
+    }
+    /// Set the task priority
+    pub fn set_tpr(&mut self, tpr: u32) {
+        self.set_reg(APIC_TPR, tpr)
+    }
+    /// Spurious interrupt vector register
+    pub fn spiv(&self) -> u32 {
+        self.reg(APIC_SPIV)
+    }
+    /// Set the spurious interrupt vector register
+    pub fn set_spiv(&mut self, spiv: u32) {
+        self.set_reg(APIC_SPIV, spiv)
+    }
+    /// Local vector table entry
+    pub fn lvt(&self, lvt: Lvt) -> LvtEntry {
+        LvtEntry(self.reg(lvt.offset()))
+    }
+    /// Set a local vector table entry
+    pub fn set_lvt(&mut self, lvt: Lvt, entry: LvtEntry) {
+        self.set_reg(lvt.offset(), entry.0)
+    }
+    /// Interrupt command register, with the destination in bits 32-63
+    pub fn icr(&self) -> u64 {
+        (self.reg(APIC_ICR2) as u64) << 32 | self.reg(APIC_ICR) as u64
+    }
+    /// Set the interrupt command register
+    pub fn set_icr(&mut self, icr: u64) {
+        self.set_reg(APIC_ICR2, (icr >> 32) as u32);
+        self.set_reg(APIC_ICR, icr as u32);
+    }
+    /// Timer initial count
+    pub fn timer_initial_count(&self) -> u32 {
+        self.reg(APIC_TMICT)
+    }
+    /// Set the timer initial count
+    pub fn set_timer_initial_count(&mut self, count: u32) {
+        self.set_reg(APIC_TMICT, count)
+    }
+    /// Timer current count
+    pub fn timer_current_count(&self) -> u32 {
+        self.reg(APIC_TMCCT)
+    }
+    /// Timer divide configuration register
+    pub fn timer_divide_config(&self) -> u32 {
+        self.reg(APIC_TDCR)
+    }
+    /// Set the timer divide configuration register
+    pub fn set_timer_divide_config(&mut self, tdcr: u32) {
+        self.set_reg(APIC_TDCR, tdcr)
+    }
+    /// Value the bus clock is divided by for the timer
+    pub fn timer_divisor(&self) -> u32 {
+        let tdcr = self.timer_divide_config();
+        let shift = (tdcr & 0x3) | ((tdcr & 0x8) >> 1);
+        if shift == 7 { 1 } else { 2 << shift }
+    }
+}
 #[repr(C)]
 #[derive(Copy)]
 pub struct Segment {
@@ -261,6 +583,114 @@ impl ::std::default::Default for Fpu {
         unsafe { ::std::mem::zeroed() }
     }
 }
+
+impl Fpu {
+    /// x87 register `ST(i)`
+    pub fn st(&self, i: usize) -> X87Register {
+        let fpr = &self.fpr[i];
+        let mut mantissa = 0;
+        for (j, &b) in fpr[..8].iter().enumerate() {
+            mantissa |= (b as u64) << (j * 8);
+        }
+        X87Register {
+            mantissa,
+            sign_exponent: fpr[8] as u16 | (fpr[9] as u16) << 8,
+        }
+    }
+
+    /// Set x87 register `ST(i)`
+    pub fn set_st(&mut self, i: usize, reg: X87Register) {
+        let fpr = &mut self.fpr[i];
+        for (j, b) in fpr[..8].iter_mut().enumerate() {
+            *b = (reg.mantissa >> (j * 8)) as u8;
+        }
+        fpr[8] = reg.sign_exponent as u8;
+        fpr[9] = (reg.sign_exponent >> 8) as u8;
+    }
+
+    /// Register `XMMi`
+    pub fn xmm(&self, i: usize) -> u128 {
+        let mut val = 0;
+        for (j, &b) in self.xmm[i].iter().enumerate() {
+            val |= (b as u128) << (j * 8);
+        }
+        val
+    }
+
+    /// Set register `XMMi`
+    pub fn set_xmm(&mut self, i: usize, val: u128) {
+        for (j, b) in self.xmm[i].iter_mut().enumerate() {
+            *b = (val >> (j * 8)) as u8;
+        }
+    }
+}
+
+const X87_EXPONENT_BIAS: i32 = 16383;
+const X87_EXPONENT_MAX: u16 = 0x7fff;
+const X87_SIGN: u16 = 1 << 15;
+const X87_INTEGER_BIT: u64 = 1 << 63;
+
+/// An x87 80-bit extended precision value
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct X87Register {
+    /// Significand, including the explicit integer bit
+    pub mantissa: u64,
+    /// Sign in bit 15 and biased exponent in bits 0-14
+    pub sign_exponent: u16,
+}
+
+impl X87Register {
+    /// Convert to the nearest `f64`
+    pub fn to_f64(&self) -> f64 {
+        let sign = if self.sign_exponent & X87_SIGN != 0 {
+            -1.0
+        } else {
+            1.0
+        };
+        let exponent = self.sign_exponent & X87_EXPONENT_MAX;
+        if exponent == X87_EXPONENT_MAX {
+            return if self.mantissa << 1 == 0 {
+                sign * f64::INFINITY
+            } else {
+                f64::NAN
+            };
+        }
+        // Denormals use the same exponent as the smallest normal value
+        let exponent = if exponent == 0 { 1 } else { exponent as i32 };
+        // value = mantissa * 2^(exponent - bias - 63), scaled in two steps
+        // so that the intermediate powers of two stay representable
+        let scale = exponent - X87_EXPONENT_BIAS - 63;
+        let half = scale / 2;
+        sign * self.mantissa as f64 * 2f64.powi(half) * 2f64.powi(scale - half)
+    }
+
+    /// Convert from an `f64`, which is always exact
+    pub fn from_f64(val: f64) -> Self {
+        let bits = val.to_bits();
+        let sign = if bits >> 63 != 0 { X87_SIGN } else { 0 };
+        let exponent = ((bits >> 52) & 0x7ff) as i32;
+        let fraction = bits & ((1 << 52) - 1);
+        let (mantissa, exponent) = if exponent == 0x7ff {
+            (X87_INTEGER_BIT | fraction << 11, X87_EXPONENT_MAX)
+        } else if exponent == 0 {
+            if fraction == 0 {
+                (0, 0)
+            } else {
+                // Normalize the subnormal
+                let shift = fraction.leading_zeros() as i32;
+                (fraction << shift,
+                 (X87_EXPONENT_BIAS - 1074 - shift + 63) as u16)
+            }
+        } else {
+            (X87_INTEGER_BIT | fraction << 11,
+             (exponent - 1023 + X87_EXPONENT_BIAS) as u16)
+        };
+        X87Register {
+            mantissa,
+            sign_exponent: sign | exponent,
+        }
+    }
+}
 #[repr(C)]
 #[derive(Copy)]
 pub struct MsrEntry {
@@ -295,6 +725,114 @@ impl ::std::default::Default for Msrs {
         unsafe { ::std::mem::zeroed() }
     }
 }
+
+pub struct MsrHandle {
+    msrs: *mut Msrs,
+}
+
+impl MsrHandle {
+    pub fn new(nmsrs: u32) -> MsrHandle {
+        unsafe {
+            let sz = size_of::<Msrs>() +
+                     nmsrs as usize * size_of::<MsrEntry>();
+            let ptr = calloc(1, sz) as *mut Msrs;
+            assert!(!ptr.is_null());
+            (*ptr).nmsrs = nmsrs;
+            MsrHandle { msrs: ptr }
+        }
+    }
+
+    /// Allocate entries for the given MSR indices
+    pub fn from_indices(indices: &[u32]) -> MsrHandle {
+        let mut h = MsrHandle::new(indices.len() as u32);
+        for (entry, &index) in h.entries_mut().iter_mut().zip(indices) {
+            entry.index = index;
+        }
+        h
+    }
+
+    /// Allocate entries for the given MSR index and value pairs
+    pub fn from_entries(entries: &[(u32, u64)]) -> MsrHandle {
+        let mut h = MsrHandle::new(entries.len() as u32);
+        for (entry, &(index, data)) in
+            h.entries_mut().iter_mut().zip(entries) {
+            entry.index = index;
+            entry.data = data;
+        }
+        h
+    }
+}
+
+impl Deref for MsrHandle {
+    type Target = Msrs;
+
+    fn deref(&self) -> &Msrs {
+        unsafe { &*self.msrs }
+    }
+}
+
+impl DerefMut for MsrHandle {
+    fn deref_mut(&mut self) -> &mut Msrs {
+        unsafe { &mut *self.msrs }
+    }
+}
+
+impl Drop for MsrHandle {
+    fn drop(&mut self) {
+        unsafe {
+            free(self.msrs as *mut c_void);
+        }
+    }
+}
+
+impl Msrs {
+    pub fn entries(&self) -> &[MsrEntry] {
+        unsafe {
+            slice::from_raw_parts(self.entries.as_ptr(), self.nmsrs as usize)
+        }
+    }
+    pub fn entries_mut(&mut self) -> &mut [MsrEntry] {
+        unsafe {
+            slice::from_raw_parts_mut(self.entries.as_mut_ptr(),
+                                      self.nmsrs as usize)
+        }
+    }
+    /// Value of the MSR with the given index, if present
+    pub fn get(&self, index: u32) -> Option<u64> {
+        self.entries().iter().find(|e| e.index == index).map(|e| e.data)
+    }
+}
+
+/// Time stamp counter
+pub const MSR_IA32_TSC: u32 = 0x10;
+/// Local APIC base address and mode
+pub const MSR_IA32_APICBASE: u32 = 0x1b;
+/// Code segment selector for `sysenter`
+pub const MSR_IA32_SYSENTER_CS: u32 = 0x174;
+/// Stack pointer for `sysenter`
+pub const MSR_IA32_SYSENTER_ESP: u32 = 0x175;
+/// Instruction pointer for `sysenter`
+pub const MSR_IA32_SYSENTER_EIP: u32 = 0x176;
+/// Miscellaneous processor features
+pub const MSR_IA32_MISC_ENABLE: u32 = 0x1a0;
+/// Page attribute table
+pub const MSR_IA32_CR_PAT: u32 = 0x277;
+/// Extended feature enables
+pub const MSR_EFER: u32 = 0xc0000080;
+/// Segment selectors for `syscall` and `sysret`
+pub const MSR_STAR: u32 = 0xc0000081;
+/// Long mode target of `syscall`
+pub const MSR_LSTAR: u32 = 0xc0000082;
+/// Compatibility mode target of `syscall`
+pub const MSR_CSTAR: u32 = 0xc0000083;
+/// RFLAGS mask for `syscall`
+pub const MSR_SYSCALL_MASK: u32 = 0xc0000084;
+/// Base address of FS
+pub const MSR_FS_BASE: u32 = 0xc0000100;
+/// Base address of GS
+pub const MSR_GS_BASE: u32 = 0xc0000101;
+/// GS base swapped in by `swapgs`
+pub const MSR_KERNEL_GS_BASE: u32 = 0xc0000102;
 #[repr(C)]
 #[derive(Copy)]
 pub struct MsrList {
@@ -385,8 +923,8 @@ pub struct CpuidHandle {
 impl CpuidHandle {
     pub fn new(nent: u32) -> CpuidHandle {
         unsafe {
-            let sz = mem::size_of::<Cpuid2>() +
-                     nent as usize * mem::size_of::<CpuidEntry2>();
+            let sz = size_of::<Cpuid2>() +
+                     nent as usize * size_of::<CpuidEntry2>();
             let ptr = calloc(1, sz) as *mut Cpuid2;
             assert!(!ptr.is_null());
             (*ptr).nent = nent;
@@ -422,7 +960,7 @@ impl Cpuid2 {
         unsafe {
             let begin: *const Cpuid2 = self;
             let first_ent = (begin as *const u8)
-                                .offset(mem::size_of::<Cpuid2>() as isize);
+                                .add(size_of::<Cpuid2>());
             slice::from_raw_parts(first_ent as *const _, self.nent as usize)
         }
     }
@@ -430,7 +968,7 @@ impl Cpuid2 {
         unsafe {
             let begin: *mut Cpuid2 = self;
             let first_ent = (begin as *mut u8)
-                                .offset(mem::size_of::<Cpuid2>() as isize);
+                                .add(size_of::<Cpuid2>());
             slice::from_raw_parts_mut(first_ent as *mut _, self.nent as usize)
         }
     }
@@ -529,6 +1067,10 @@ impl ::std::default::Default for PitState2 {
         unsafe { ::std::mem::zeroed() }
     }
 }
+/// `PitState2::flags` bit set when the HPET is in legacy replacement mode
+pub const PIT_FLAGS_HPET_LEGACY: u32 = 1;
+/// `PitState2::flags` bit set when the PC speaker data output is enabled
+pub const PIT_FLAGS_SPEAKER_DATA_ON: u32 = 1 << 1;
 #[repr(C)]
 #[derive(Copy)]
 pub struct ReinjectControl {
@@ -545,81 +1087,88 @@ impl ::std::default::Default for ReinjectControl {
         unsafe { ::std::mem::zeroed() }
     }
 }
+/// Pending and in-progress events of a `Vcpu`
+///
+/// `flags` says which of the optional fields are valid, as the bitwise or of
+/// the `VCPUEVENT_VALID_*` flags.
 #[repr(C)]
-#[derive(Copy)]
+#[derive(Clone, Copy, Debug, Default)]
 pub struct VcpuEvents {
-    pub exception: StructUnnamed5,
-    pub interrupt: StructUnnamed6,
-    pub nmi: StructUnnamed7,
+    pub exception: ExceptionEvent,
+    pub interrupt: InterruptEvent,
+    pub nmi: NmiEvent,
     pub sipi_vector: u32,
     pub flags: u32,
-    pub reserved: [u32; 10usize],
-}
-impl ::std::clone::Clone for VcpuEvents {
-    fn clone(&self) -> Self {
-        *self
-    }
-}
-impl ::std::default::Default for VcpuEvents {
-    fn default() -> Self {
-        unsafe { ::std::mem::zeroed() }
-    }
-}
+    pub smi: SmiEvent,
+    pub triple_fault: TripleFaultEvent,
+    pub reserved: [u8; 26usize],
+    pub exception_has_payload: u8,
+    pub exception_payload: u64,
+}
+/// An exception being delivered to the guest
+///
+/// Once `VirtualMachine::enable_exception_payload` has been called, an
+/// exception may be `pending` rather than `injected`, in which case any
+/// payload such as the faulting address of a page fault has not yet been
+/// delivered to the guest.
 #[repr(C)]
-#[derive(Copy)]
-pub struct StructUnnamed5 {
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ExceptionEvent {
     pub injected: u8,
     pub nr: u8,
     pub has_error_code: u8,
-    pub pad: u8,
+    pub pending: u8,
     pub error_code: u32,
 }
-impl ::std::clone::Clone for StructUnnamed5 {
-    fn clone(&self) -> Self {
-        *self
-    }
-}
-impl ::std::default::Default for StructUnnamed5 {
-    fn default() -> Self {
-        unsafe { ::std::mem::zeroed() }
-    }
-}
+/// An external or software interrupt being delivered to the guest
+///
+/// `shadow` is only valid with `VCPUEVENT_VALID_SHADOW`.
 #[repr(C)]
-#[derive(Copy)]
-pub struct StructUnnamed6 {
+#[derive(Clone, Copy, Debug, Default)]
+pub struct InterruptEvent {
     pub injected: u8,
     pub nr: u8,
     pub soft: u8,
     pub shadow: u8,
 }
-impl ::std::clone::Clone for StructUnnamed6 {
-    fn clone(&self) -> Self {
-        *self
-    }
-}
-impl ::std::default::Default for StructUnnamed6 {
-    fn default() -> Self {
-        unsafe { ::std::mem::zeroed() }
-    }
-}
+/// Non-maskable interrupt state
+///
+/// `pending` is only valid with `VCPUEVENT_VALID_NMI_PENDING`.
 #[repr(C)]
-#[derive(Copy)]
-pub struct StructUnnamed7 {
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NmiEvent {
     pub injected: u8,
     pub pending: u8,
     pub masked: u8,
     pub pad: u8,
 }
-impl ::std::clone::Clone for StructUnnamed7 {
-    fn clone(&self) -> Self {
-        *self
-    }
+/// System management mode state, valid with `VCPUEVENT_VALID_SMM`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SmiEvent {
+    pub smm: u8,
+    pub pending: u8,
+    pub smm_inside_nmi: u8,
+    pub latched_init: u8,
 }
-impl ::std::default::Default for StructUnnamed7 {
-    fn default() -> Self {
-        unsafe { ::std::mem::zeroed() }
-    }
+/// Triple fault state, valid with `VCPUEVENT_VALID_TRIPLE_FAULT`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TripleFaultEvent {
+    pub pending: u8,
 }
+/// `VcpuEvents::nmi.pending` is valid
+pub const VCPUEVENT_VALID_NMI_PENDING: u32 = 1;
+/// `VcpuEvents::sipi_vector` is valid
+pub const VCPUEVENT_VALID_SIPI_VECTOR: u32 = 1 << 1;
+/// `VcpuEvents::interrupt.shadow` is valid
+pub const VCPUEVENT_VALID_SHADOW: u32 = 1 << 2;
+/// `VcpuEvents::smi` is valid
+pub const VCPUEVENT_VALID_SMM: u32 = 1 << 3;
+/// `VcpuEvents::exception.pending` and the exception payload are valid
+pub const VCPUEVENT_VALID_PAYLOAD: u32 = 1 << 4;
+/// `VcpuEvents::triple_fault` is valid
+pub const VCPUEVENT_VALID_TRIPLE_FAULT: u32 = 1 << 5;
 #[repr(C)]
 #[derive(Copy)]
 pub struct Debugregs {